@@ -426,4 +426,93 @@ mod test {
         let prover = MockProver::<Fr>::run(TestCircuit1::<Fr>::K as u32, &circuit, vec![]).unwrap();
         prover.verify().unwrap();
     }
+
+    /// Collects the base point and a multiple of it as computed by the
+    /// circuit's own curve code.
+    #[derive(Debug, Default)]
+    pub struct CurvePointsCircuit<F: PrimeField> {
+        scalar: F,
+        points: std::cell::RefCell<Vec<(F, F)>>,
+    }
+
+    impl<F: PrimeField> Circuit<F> for CurvePointsCircuit<F> {
+        type Config = ConfidentialTransferConfig<F>;
+        type FloorPlanner = SimpleFloorPlanner;
+        fn without_witnesses(&self) -> Self {
+            Self {
+                scalar: F::one(),
+                points: Default::default(),
+            }
+        }
+
+        fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+            TestCircuit1::configure(meta)
+        }
+
+        fn synthesize(
+            &self,
+            config: Self::Config,
+            mut layouter: impl Layouter<F>,
+        ) -> Result<(), Error> {
+            let mut first_pass = SKIP_FIRST_PASS;
+            layouter.assign_region(
+                || "curve points",
+                |region| {
+                    if first_pass {
+                        first_pass = false;
+                        return Ok(());
+                    }
+                    let gate = config.ecc_config.gate.clone();
+                    let mut aux = Context::new(
+                        region,
+                        ContextParams {
+                            max_rows: gate.max_rows,
+                            num_context_ids: 1,
+                            fixed_columns: gate.constants.clone(),
+                        },
+                    );
+                    let ctx = &mut aux;
+                    let base_point = config.ecc_config.load_base_point(ctx);
+                    let scalar = gate.load_witness(ctx, Value::known(self.scalar));
+                    let point = config.ecc_config.scalar_mul(ctx, &base_point, &scalar);
+                    for point in [&base_point, &point] {
+                        point.x.value().zip(point.y.value()).map(|(x, y)| {
+                            self.points.borrow_mut().push((*x, *y));
+                        });
+                    }
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    fn to_hash_point(x: &Fr, y: &Fr) -> hash::bn256::Affine {
+        let mut bytes = [0u8; 64];
+        for (chunk, coordinate) in bytes.chunks_mut(32).zip([x, y]) {
+            let le = halo2_base::utils::fe_to_biguint(coordinate).to_bytes_le();
+            chunk[..le.len()].copy_from_slice(&le);
+        }
+        hash::bn256::Affine::from_uncompressed(&bytes).unwrap()
+    }
+
+    /// The `hash` crate encodes balances and keys as Grumpkin points; check
+    /// that this is the curve the transfer circuit computes on.
+    #[test]
+    fn test_hash_encoding_curve() {
+        let circuit = CurvePointsCircuit {
+            scalar: Fr::random(&mut OsRng),
+            points: Default::default(),
+        };
+        let prover = MockProver::<Fr>::run(TestCircuit1::<Fr>::K as u32, &circuit, vec![]).unwrap();
+        prover.verify().unwrap();
+
+        let points = circuit.points.into_inner();
+        assert_eq!(points.len(), 2);
+        for (x, y) in points.iter() {
+            let point = to_hash_point(x, y);
+            assert!(point.is_on_curve());
+            let decoded = hash::bn256::Affine::from_compressed(&point.to_compressed()).unwrap();
+            assert_eq!(decoded.to_uncompressed(), point.to_uncompressed());
+        }
+    }
 }
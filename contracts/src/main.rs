@@ -119,16 +119,17 @@ fn main() {
     let leaves = [Fr::random(rng), Fr::random(rng), Fr::random(rng)];
     const HEIGHT: usize = 3;
 
-    let circuit = MerkleCircuit::<Fr, SmtP128Pow5T3<Fr, 0>, Poseidon<Fr, 2>, 3, 2, HEIGHT>::new(
+    let circuit = MerkleCircuit::<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2, HEIGHT>::new(
         leaves,
         empty_leaf,
         Poseidon::<Fr, 2>::new(),
-    );
+    )
+    .expect("three leaves fit a height-3 tree");
     let pk = gen_pk(&params, &circuit);
     let deployment_code = gen_evm_verifier(
         &params,
         pk.get_vk(),
-        MerkleCircuit::<Fr, SmtP128Pow5T3<Fr, 0>, Poseidon<Fr, 2>, 3, 2, HEIGHT>::num_instance(),
+        MerkleCircuit::<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2, HEIGHT>::num_instance(),
     );
 
     let proof = gen_proof(&params, &pk, circuit.clone(), circuit.instances());
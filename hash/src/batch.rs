@@ -0,0 +1,200 @@
+use halo2_proofs::{
+    arithmetic::FieldExt,
+    circuit::{Layouter, SimpleFloorPlanner, Value},
+    plonk::{Advice, Circuit, Column, ConstraintSystem, Error, Instance},
+};
+
+use std::convert::TryInto;
+use std::marker::PhantomData;
+
+use crate::{leaf_to_message, Affine, EncryptedBalance, LeafDomain, LeafHashInstructions};
+
+/// Hashes `N` leaves in one proof, exposing digest `i` at instance row `i`.
+///
+/// The leaves are hashed with the chip `C`, so the same circuit serves Pow5
+/// (`LeafHashChip`) and, with the `poseidon2` feature, `Poseidon2Chip`. The
+/// permutations are spread over `LANES` chip configurations with disjoint
+/// columns. The floor planner places regions on disjoint columns side by side,
+/// so `LANES` leaves are hashed in the rows one permutation would take.
+#[derive(Clone)]
+pub struct BatchHashCircuit<F, C, const N: usize, const LANES: usize>
+where
+    F: FieldExt,
+    C: LeafHashInstructions<F> + Clone,
+{
+    messages: Value<[[F; 8]; N]>,
+    domain: LeafDomain,
+    _chip: PhantomData<C>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LaneConfig<F: FieldExt, C: LeafHashInstructions<F>> {
+    input: [Column<Advice>; 7],
+    leaf_hash_config: C::Config,
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchHashConfig<F: FieldExt, C: LeafHashInstructions<F>> {
+    lanes: Vec<LaneConfig<F, C>>,
+    digests: Column<Instance>,
+}
+
+impl<F, C, const N: usize, const LANES: usize> BatchHashCircuit<F, C, N, LANES>
+where
+    F: FieldExt,
+    C: LeafHashInstructions<F> + Clone,
+{
+    pub fn from_leaves(
+        leaves: [(EncryptedBalance<F>, Affine<F>, F); N],
+        domain: LeafDomain,
+    ) -> Self {
+        Self {
+            messages: Value::known(leaves.map(|(balance, public_key, nonce)| {
+                leaf_to_message(balance, public_key, nonce, domain)
+            })),
+            domain,
+            _chip: PhantomData,
+        }
+    }
+
+    pub fn num_instance() -> Vec<usize> {
+        vec![N]
+    }
+}
+
+impl<F, C, const N: usize, const LANES: usize> Circuit<F> for BatchHashCircuit<F, C, N, LANES>
+where
+    F: FieldExt,
+    C: LeafHashInstructions<F> + Clone,
+{
+    type Config = BatchHashConfig<F, C>;
+    type FloorPlanner = SimpleFloorPlanner;
+
+    fn without_witnesses(&self) -> Self {
+        Self {
+            messages: Value::unknown(),
+            domain: self.domain,
+            _chip: PhantomData,
+        }
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        assert!(LANES > 0);
+
+        let digests = meta.instance_column();
+        meta.enable_equality(digests);
+
+        let lanes = (0..LANES)
+            .map(|_| {
+                let (leaf_hash_config, input) = C::configure_columns(meta);
+                LaneConfig {
+                    input,
+                    leaf_hash_config,
+                }
+            })
+            .collect();
+
+        BatchHashConfig { lanes, digests }
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>,
+    ) -> Result<(), Error> {
+        for i in 0..N {
+            let lane = &config.lanes[i % LANES];
+
+            let cells = layouter.assign_region(
+                || format!("load leaf {}", i),
+                |mut region| {
+                    (0..7)
+                        .map(|j| {
+                            region.assign_advice(
+                                || format!("load leaf_{}_{}", i, j),
+                                lane.input[j],
+                                0,
+                                || self.messages.map(|messages| messages[i][j]),
+                            )
+                        })
+                        .collect::<Result<Vec<_>, Error>>()
+                },
+            )?;
+
+            let chip = C::construct(lane.leaf_hash_config.clone());
+            let digest = chip.hash_leaf(
+                layouter.namespace(|| format!("hash leaf {}", i)),
+                &cells[..4].to_vec().try_into().unwrap(),
+                &cells[4..6].to_vec().try_into().unwrap(),
+                &cells[6],
+                self.domain,
+            )?;
+
+            layouter.constrain_instance(digest.cell(), config.digests, i)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hash_leaf, LeafHashChip, MySpec};
+    use ff::Field;
+    use halo2_proofs::dev::MockProver;
+    use halo2curves::bn256::Fr;
+    use rand::rngs::OsRng;
+
+    const N: usize = 4;
+
+    /// Proves `N` random leaves with chip `C`, against digests from the
+    /// matching native hash `native`.
+    fn batch_test<C>(k: u32, native: fn(EncryptedBalance<Fr>, Affine<Fr>, Fr, LeafDomain) -> Fr)
+    where
+        C: LeafHashInstructions<Fr> + Clone,
+    {
+        let point = || Affine {
+            x: Fr::random(OsRng),
+            y: Fr::random(OsRng),
+        };
+        let leaves = [(); N].map(|_| {
+            (
+                EncryptedBalance {
+                    left: point(),
+                    right: point(),
+                },
+                point(),
+                Fr::random(OsRng),
+            )
+        });
+        let digests: Vec<Fr> = leaves
+            .iter()
+            .map(|(balance, public_key, nonce)| {
+                native(*balance, *public_key, *nonce, LeafDomain::new(1))
+            })
+            .collect();
+
+        let circuit = BatchHashCircuit::<Fr, C, N, 2>::from_leaves(leaves, LeafDomain::new(1));
+        let prover = MockProver::run(k, &circuit, vec![digests.clone()]).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        let mut swapped = digests;
+        swapped.swap(0, 1);
+        let prover = MockProver::run(k, &circuit, vec![swapped]).unwrap();
+        assert!(prover.verify().is_err());
+    }
+
+    #[test]
+    fn batch_hash_test() {
+        batch_test::<LeafHashChip<Fr, MySpec<9, 8>, 9, 8>>(8, hash_leaf);
+    }
+
+    #[cfg(feature = "poseidon2")]
+    #[test]
+    fn poseidon2_batch_hash_test() {
+        use crate::poseidon2::{self, Poseidon2Bn256T12, Poseidon2Chip};
+
+        batch_test::<Poseidon2Chip<Fr, Poseidon2Bn256T12, 12, 11>>(7, poseidon2::hash_leaf);
+    }
+}
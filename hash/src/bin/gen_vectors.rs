@@ -0,0 +1,11 @@
+use hash::vectors::TestVectors;
+use std::{env, fs};
+
+/// Writes the known-answer vectors to the given path, or to stdout.
+fn main() {
+    let json = serde_json::to_string_pretty(&TestVectors::generate()).unwrap();
+    match env::args().nth(1) {
+        Some(path) => fs::write(path, json + "\n").unwrap(),
+        None => println!("{}", json),
+    }
+}
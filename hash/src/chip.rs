@@ -0,0 +1,388 @@
+use halo2_proofs::{
+    arithmetic::FieldExt,
+    circuit::{AssignedCell, Layouter},
+    plonk::{Advice, Column, ConstraintSystem, Error, Fixed},
+};
+
+use halo2_gadgets::poseidon::{
+    primitives::{ConstantLength, Domain, Spec},
+    PaddedWord, Pow5Chip, Pow5Config, Sponge,
+};
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+
+use crate::LeafDomain;
+
+/// A chip that constrains leaf digests, so that circuits such as
+/// `BatchHashCircuit` can be built over either permutation.
+pub trait LeafHashInstructions<F: FieldExt>: Sized {
+    type Config: Clone + Debug;
+
+    /// Allocates the chip's columns and configures it, returning the config
+    /// and seven equality-enabled advice columns to load the leaf words into.
+    fn configure_columns(meta: &mut ConstraintSystem<F>) -> (Self::Config, [Column<Advice>; 7]);
+
+    fn construct(config: Self::Config) -> Self;
+
+    /// Returns the cell holding the digest of
+    /// `leaf_to_message(balance, public_key, nonce, domain)` under the chip's
+    /// permutation.
+    fn hash_leaf(
+        &self,
+        layouter: impl Layouter<F>,
+        balance: &[AssignedCell<F, F>; 4],
+        public_key: &[AssignedCell<F, F>; 2],
+        nonce: &AssignedCell<F, F>,
+        domain: LeafDomain,
+    ) -> Result<AssignedCell<F, F>, Error>;
+}
+
+#[derive(Debug, Clone)]
+pub struct LeafHashConfig<F: FieldExt, const WIDTH: usize, const RATE: usize> {
+    poseidon_config: Pow5Config<F, WIDTH, RATE>,
+}
+
+/// Constrains the leaf digest of already assigned ciphertext, public key and
+/// nonce cells, so that other circuits can bind those cells to a tree leaf.
+#[derive(Debug, Clone)]
+pub struct LeafHashChip<F: FieldExt, S: Spec<F, WIDTH, RATE>, const WIDTH: usize, const RATE: usize>
+{
+    config: LeafHashConfig<F, WIDTH, RATE>,
+    _spec: PhantomData<S>,
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize> LeafHashChip<F, S, WIDTH, RATE>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+{
+    /// Configures the underlying Pow5 permutation and enables `rc_b[0]` as a
+    /// constant column for the sponge's initial capacity.
+    pub fn configure(
+        meta: &mut ConstraintSystem<F>,
+        state: [Column<Advice>; WIDTH],
+        partial_sbox: Column<Advice>,
+        rc_a: [Column<Fixed>; WIDTH],
+        rc_b: [Column<Fixed>; WIDTH],
+    ) -> LeafHashConfig<F, WIDTH, RATE> {
+        assert!(RATE >= 8, "a leaf is absorbed in a single block");
+        meta.enable_constant(rc_b[0]);
+
+        LeafHashConfig {
+            poseidon_config: Pow5Chip::configure::<S>(meta, state, partial_sbox, rc_a, rc_b),
+        }
+    }
+
+    pub fn construct(config: LeafHashConfig<F, WIDTH, RATE>) -> Self {
+        Self {
+            config,
+            _spec: PhantomData,
+        }
+    }
+
+    /// Returns the cell holding the digest of
+    /// `leaf_to_message(balance, public_key, nonce, domain)`.
+    ///
+    /// `balance` is `[left.x, left.y, right.x, right.y]` and `public_key` is `[x, y]`.
+    /// The domain header is fixed by the circuit rather than witnessed.
+    pub fn hash_leaf(
+        &self,
+        layouter: impl Layouter<F>,
+        balance: &[AssignedCell<F, F>; 4],
+        public_key: &[AssignedCell<F, F>; 2],
+        nonce: &AssignedCell<F, F>,
+        domain: LeafDomain,
+    ) -> Result<AssignedCell<F, F>, Error> {
+        let words = leaf_words(balance, public_key, nonce, domain);
+        self.hash::<8>(layouter, words.into_iter())
+    }
+
+    /// Returns the cell holding `address_from_pubkey` of the assigned `[x, y]` key.
+    pub fn address_from_pubkey(
+        &self,
+        layouter: impl Layouter<F>,
+        public_key: &[AssignedCell<F, F>; 2],
+    ) -> Result<AssignedCell<F, F>, Error> {
+        let words = public_key
+            .iter()
+            .map(|cell| PaddedWord::Message(cell.clone()));
+        self.hash::<2>(layouter, words)
+    }
+
+    fn hash<const L: usize>(
+        &self,
+        mut layouter: impl Layouter<F>,
+        words: impl Iterator<Item = PaddedWord<F>>,
+    ) -> Result<AssignedCell<F, F>, Error> {
+        let chip = Pow5Chip::construct(self.config.poseidon_config.clone());
+
+        let mut sponge = Sponge::<_, _, S, _, ConstantLength<L>, WIDTH, RATE>::new(
+            chip,
+            layouter.namespace(|| "init"),
+        )?;
+        for (i, word) in words.enumerate() {
+            sponge.absorb(layouter.namespace(|| format!("absorb_{}", i)), word)?;
+        }
+        for (i, pad) in <ConstantLength<L> as Domain<F, RATE>>::padding(L)
+            .into_iter()
+            .enumerate()
+        {
+            sponge.absorb(
+                layouter.namespace(|| format!("absorb_padding_{}", i)),
+                PaddedWord::Padding(pad),
+            )?;
+        }
+
+        sponge
+            .finish_absorbing(layouter.namespace(|| "finish absorbing"))?
+            .squeeze(layouter.namespace(|| "squeeze"))
+    }
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize> LeafHashInstructions<F>
+    for LeafHashChip<F, S, WIDTH, RATE>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+{
+    type Config = LeafHashConfig<F, WIDTH, RATE>;
+
+    fn configure_columns(meta: &mut ConstraintSystem<F>) -> (Self::Config, [Column<Advice>; 7]) {
+        let state = [(); WIDTH].map(|_| meta.advice_column());
+        let partial_sbox = meta.advice_column();
+        let rc_a = [(); WIDTH].map(|_| meta.fixed_column());
+        let rc_b = [(); WIDTH].map(|_| meta.fixed_column());
+
+        let config = Self::configure(meta, state, partial_sbox, rc_a, rc_b);
+        (config, state[..7].try_into().unwrap())
+    }
+
+    fn construct(config: Self::Config) -> Self {
+        Self::construct(config)
+    }
+
+    fn hash_leaf(
+        &self,
+        layouter: impl Layouter<F>,
+        balance: &[AssignedCell<F, F>; 4],
+        public_key: &[AssignedCell<F, F>; 2],
+        nonce: &AssignedCell<F, F>,
+        domain: LeafDomain,
+    ) -> Result<AssignedCell<F, F>, Error> {
+        Self::hash_leaf(self, layouter, balance, public_key, nonce, domain)
+    }
+}
+
+/// The sponge inputs of a leaf, in `leaf_to_message` order.
+pub(crate) fn leaf_words<F: FieldExt>(
+    balance: &[AssignedCell<F, F>; 4],
+    public_key: &[AssignedCell<F, F>; 2],
+    nonce: &AssignedCell<F, F>,
+    domain: LeafDomain,
+) -> Vec<PaddedWord<F>> {
+    balance
+        .iter()
+        .chain(public_key.iter())
+        .chain(Some(nonce))
+        .map(|cell| PaddedWord::Message(cell.clone()))
+        // The header slot is fixed, so it is loaded as a constant.
+        .chain(Some(PaddedWord::Padding(domain.header())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address_from_pubkey, hash_leaf, leaf_to_message, Affine, EncryptedBalance, LeafDomain,
+        MySpec,
+    };
+    use ff::Field;
+    use halo2_proofs::{
+        circuit::{SimpleFloorPlanner, Value},
+        dev::MockProver,
+        plonk::{Circuit, Instance},
+    };
+    use halo2curves::bn256::Fr;
+    use rand::rngs::OsRng;
+
+    const DOMAIN: LeafDomain = LeafDomain {
+        version: LeafDomain::VERSION,
+        chain_id: 1,
+    };
+
+    #[derive(Clone)]
+    struct LeafCircuit {
+        message: Value<[Fr; 8]>,
+    }
+
+    impl Circuit<Fr> for LeafCircuit {
+        type Config = (
+            LeafHashConfig<Fr, 9, 8>,
+            [Column<Advice>; 9],
+            Column<Instance>,
+        );
+        type FloorPlanner = SimpleFloorPlanner;
+
+        fn without_witnesses(&self) -> Self {
+            Self {
+                message: Value::unknown(),
+            }
+        }
+
+        fn configure(meta: &mut ConstraintSystem<Fr>) -> Self::Config {
+            let state = [(); 9].map(|_| meta.advice_column());
+            let partial_sbox = meta.advice_column();
+            let rc_a = [(); 9].map(|_| meta.fixed_column());
+            let rc_b = [(); 9].map(|_| meta.fixed_column());
+            let expected = meta.instance_column();
+            meta.enable_equality(expected);
+
+            let config = LeafHashChip::<Fr, MySpec<9, 8>, 9, 8>::configure(
+                meta,
+                state,
+                partial_sbox,
+                rc_a,
+                rc_b,
+            );
+            (config, state, expected)
+        }
+
+        fn synthesize(
+            &self,
+            (config, state, expected): Self::Config,
+            mut layouter: impl Layouter<Fr>,
+        ) -> Result<(), Error> {
+            let cells = layouter.assign_region(
+                || "load leaf",
+                |mut region| {
+                    (0..7)
+                        .map(|i| {
+                            region.assign_advice(
+                                || format!("leaf_{}", i),
+                                state[i],
+                                0,
+                                || self.message.map(|message| message[i]),
+                            )
+                        })
+                        .collect::<Result<Vec<_>, Error>>()
+                },
+            )?;
+
+            let chip = LeafHashChip::<Fr, MySpec<9, 8>, 9, 8>::construct(config);
+            let public_key = cells[4..6].to_vec().try_into().unwrap();
+            let digest = chip.hash_leaf(
+                layouter.namespace(|| "hash leaf"),
+                &cells[..4].to_vec().try_into().unwrap(),
+                &public_key,
+                &cells[6],
+                DOMAIN,
+            )?;
+            let address =
+                chip.address_from_pubkey(layouter.namespace(|| "address"), &public_key)?;
+
+            layouter.constrain_instance(digest.cell(), expected, 0)?;
+            layouter.constrain_instance(address.cell(), expected, 1)
+        }
+    }
+
+    #[derive(Clone)]
+    struct AddressCircuit {
+        public_key: Value<[Fr; 2]>,
+    }
+
+    impl Circuit<Fr> for AddressCircuit {
+        type Config = <LeafCircuit as Circuit<Fr>>::Config;
+        type FloorPlanner = SimpleFloorPlanner;
+
+        fn without_witnesses(&self) -> Self {
+            Self {
+                public_key: Value::unknown(),
+            }
+        }
+
+        fn configure(meta: &mut ConstraintSystem<Fr>) -> Self::Config {
+            LeafCircuit::configure(meta)
+        }
+
+        fn synthesize(
+            &self,
+            (config, state, expected): Self::Config,
+            mut layouter: impl Layouter<Fr>,
+        ) -> Result<(), Error> {
+            let public_key = layouter.assign_region(
+                || "load key",
+                |mut region| {
+                    let mut load = |i: usize| {
+                        region.assign_advice(
+                            || format!("key_{}", i),
+                            state[i],
+                            0,
+                            || self.public_key.map(|key| key[i]),
+                        )
+                    };
+                    Ok([load(0)?, load(1)?])
+                },
+            )?;
+
+            let chip = LeafHashChip::<Fr, MySpec<9, 8>, 9, 8>::construct(config);
+            let address =
+                chip.address_from_pubkey(layouter.namespace(|| "address"), &public_key)?;
+            layouter.constrain_instance(address.cell(), expected, 0)
+        }
+    }
+
+    #[test]
+    fn address_chip_test() {
+        let public_key = Affine {
+            x: Fr::random(OsRng),
+            y: Fr::random(OsRng),
+        };
+        let circuit = AddressCircuit {
+            public_key: Value::known([public_key.x, public_key.y]),
+        };
+        let address = address_from_pubkey(public_key);
+
+        let prover = MockProver::run(7, &circuit, vec![vec![address]]).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        let prover = MockProver::run(7, &circuit, vec![vec![address + Fr::one()]]).unwrap();
+        assert!(prover.verify().is_err());
+    }
+
+    #[test]
+    fn leaf_hash_chip_test() {
+        let point = || Affine {
+            x: Fr::random(OsRng),
+            y: Fr::random(OsRng),
+        };
+        let balance = EncryptedBalance {
+            left: point(),
+            right: point(),
+        };
+        let public_key = point();
+        let nonce = Fr::random(OsRng);
+
+        let circuit = LeafCircuit {
+            message: Value::known(leaf_to_message(balance, public_key, nonce, DOMAIN)),
+        };
+        let expected = hash_leaf(balance, public_key, nonce, DOMAIN);
+        let address = address_from_pubkey(public_key);
+
+        let prover = MockProver::run(8, &circuit, vec![vec![expected, address]]).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        let prover =
+            MockProver::run(8, &circuit, vec![vec![expected + Fr::one(), address]]).unwrap();
+        assert!(prover.verify().is_err());
+
+        let prover = MockProver::run(8, &circuit, vec![vec![expected, expected]]).unwrap();
+        assert!(prover.verify().is_err());
+
+        // A digest from another chain does not satisfy this circuit.
+        let other = hash_leaf(balance, public_key, nonce, LeafDomain::new(2));
+        let prover = MockProver::run(8, &circuit, vec![vec![other, address]]).unwrap();
+        assert!(prover.verify().is_err());
+    }
+}
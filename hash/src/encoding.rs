@@ -0,0 +1,347 @@
+//! Canonical byte, hex and serde encodings of curve points and ciphertexts.
+//!
+//! A field element is its 32-byte little-endian representation and must be
+//! canonical (less than the modulus). A point is uncompressed as `x || y`, or
+//! compressed as `x` with the parity of `y` in the most significant bit. The
+//! identity is encoded as all zero bytes in both forms; neither curve below has
+//! a point with `x = 0`, so this is unambiguous. Hex strings are the `0x`
+//! prefixed compressed encoding, which is also what serde emits; a field
+//! element in hex is the `0x` prefixed little-endian encoding.
+
+use ff::{Field, PrimeField};
+use halo2_proofs::arithmetic::FieldExt;
+use halo2curves::{bn256::Fr, pasta::Fp};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+use crate::{Affine, EncryptedBalance};
+
+pub const FIELD_BYTES: usize = 32;
+const SIGN_MASK: u8 = 0x80;
+
+/// Base field of the short Weierstrass curve `y^2 = x^3 + b` that balances and
+/// public keys live on.
+pub trait CurveField: FieldExt {
+    fn curve_b() -> Self;
+}
+
+/// Pallas, `y^2 = x^3 + 5`.
+impl CurveField for Fp {
+    fn curve_b() -> Self {
+        Fp::from(5)
+    }
+}
+
+/// Grumpkin, `y^2 = x^3 - 17`, whose base field is the BN254 scalar field.
+///
+/// This must be the curve of `halo2_native_ec`, which the transfer circuit
+/// computes balances on; `confidential-transfer` decodes the points its
+/// circuit produces with this encoding to check that they agree.
+impl CurveField for Fr {
+    fn curve_b() -> Self {
+        -Fr::from(17)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    InvalidLength { expected: usize, actual: usize },
+    InvalidHex(hex::FromHexError),
+    NonCanonicalField,
+    NotOnCurve,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidLength { expected, actual } => {
+                write!(f, "expected {} bytes, got {}", expected, actual)
+            }
+            DecodeError::InvalidHex(err) => write!(f, "invalid hex: {}", err),
+            DecodeError::NonCanonicalField => write!(f, "field element is not canonical"),
+            DecodeError::NotOnCurve => write!(f, "point is not on the curve"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// The 32-byte little-endian representation of a field element.
+pub fn field_to_bytes<F: FieldExt>(value: &F) -> [u8; FIELD_BYTES] {
+    let mut bytes = [0u8; FIELD_BYTES];
+    bytes.copy_from_slice(value.to_repr().as_ref());
+    bytes
+}
+
+pub fn field_from_bytes<F: FieldExt>(bytes: &[u8]) -> Result<F, DecodeError> {
+    check_length(bytes, FIELD_BYTES)?;
+    let mut repr = F::Repr::default();
+    repr.as_mut().copy_from_slice(bytes);
+    Option::from(F::from_repr(repr)).ok_or(DecodeError::NonCanonicalField)
+}
+
+pub fn field_to_hex<F: FieldExt>(value: &F) -> String {
+    format!("0x{}", hex::encode(field_to_bytes(value)))
+}
+
+pub fn field_from_hex<F: FieldExt>(s: &str) -> Result<F, DecodeError> {
+    field_from_bytes(&decode_hex(s)?)
+}
+
+fn check_length(bytes: &[u8], expected: usize) -> Result<(), DecodeError> {
+    if bytes.len() != expected {
+        return Err(DecodeError::InvalidLength {
+            expected,
+            actual: bytes.len(),
+        });
+    }
+    Ok(())
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>, DecodeError> {
+    let stripped = s.strip_prefix("0x").unwrap_or(s);
+    hex::decode(stripped).map_err(DecodeError::InvalidHex)
+}
+
+impl<F: CurveField> Affine<F> {
+    pub const COMPRESSED_BYTES: usize = FIELD_BYTES;
+    pub const UNCOMPRESSED_BYTES: usize = 2 * FIELD_BYTES;
+
+    pub fn identity() -> Self {
+        Self {
+            x: F::zero(),
+            y: F::zero(),
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.x == F::zero() && self.y == F::zero()
+    }
+
+    pub fn is_on_curve(&self) -> bool {
+        self.is_identity() || self.y.square() == self.x.square() * self.x + F::curve_b()
+    }
+
+    pub fn to_uncompressed(&self) -> [u8; 2 * FIELD_BYTES] {
+        let mut bytes = [0u8; 2 * FIELD_BYTES];
+        bytes[..FIELD_BYTES].copy_from_slice(&field_to_bytes(&self.x));
+        bytes[FIELD_BYTES..].copy_from_slice(&field_to_bytes(&self.y));
+        bytes
+    }
+
+    pub fn from_uncompressed(bytes: &[u8]) -> Result<Self, DecodeError> {
+        check_length(bytes, Self::UNCOMPRESSED_BYTES)?;
+        let point = Self {
+            x: field_from_bytes(&bytes[..FIELD_BYTES])?,
+            y: field_from_bytes(&bytes[FIELD_BYTES..])?,
+        };
+        if !point.is_on_curve() {
+            return Err(DecodeError::NotOnCurve);
+        }
+        Ok(point)
+    }
+
+    pub fn to_compressed(&self) -> [u8; FIELD_BYTES] {
+        let mut bytes = field_to_bytes(&self.x);
+        if bool::from(self.y.is_odd()) {
+            bytes[FIELD_BYTES - 1] |= SIGN_MASK;
+        }
+        bytes
+    }
+
+    pub fn from_compressed(bytes: &[u8]) -> Result<Self, DecodeError> {
+        check_length(bytes, Self::COMPRESSED_BYTES)?;
+        let mut x_bytes = [0u8; FIELD_BYTES];
+        x_bytes.copy_from_slice(bytes);
+        let is_odd = x_bytes[FIELD_BYTES - 1] & SIGN_MASK != 0;
+        x_bytes[FIELD_BYTES - 1] &= !SIGN_MASK;
+
+        let x: F = field_from_bytes(&x_bytes)?;
+        if x == F::zero() && !is_odd {
+            return Ok(Self::identity());
+        }
+
+        let y: F =
+            Option::from((x.square() * x + F::curve_b()).sqrt()).ok_or(DecodeError::NotOnCurve)?;
+        let y = if bool::from(y.is_odd()) == is_odd {
+            y
+        } else {
+            -y
+        };
+        Ok(Self { x, y })
+    }
+
+    pub fn to_hex(&self) -> String {
+        format!("0x{}", hex::encode(self.to_compressed()))
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, DecodeError> {
+        Self::from_compressed(&decode_hex(s)?)
+    }
+}
+
+impl<F: CurveField> EncryptedBalance<F> {
+    pub const COMPRESSED_BYTES: usize = 2 * FIELD_BYTES;
+    pub const UNCOMPRESSED_BYTES: usize = 4 * FIELD_BYTES;
+
+    pub fn to_uncompressed(&self) -> [u8; 4 * FIELD_BYTES] {
+        let mut bytes = [0u8; 4 * FIELD_BYTES];
+        bytes[..2 * FIELD_BYTES].copy_from_slice(&self.left.to_uncompressed());
+        bytes[2 * FIELD_BYTES..].copy_from_slice(&self.right.to_uncompressed());
+        bytes
+    }
+
+    pub fn from_uncompressed(bytes: &[u8]) -> Result<Self, DecodeError> {
+        check_length(bytes, Self::UNCOMPRESSED_BYTES)?;
+        Ok(Self {
+            left: Affine::from_uncompressed(&bytes[..2 * FIELD_BYTES])?,
+            right: Affine::from_uncompressed(&bytes[2 * FIELD_BYTES..])?,
+        })
+    }
+
+    pub fn to_compressed(&self) -> [u8; 2 * FIELD_BYTES] {
+        let mut bytes = [0u8; 2 * FIELD_BYTES];
+        bytes[..FIELD_BYTES].copy_from_slice(&self.left.to_compressed());
+        bytes[FIELD_BYTES..].copy_from_slice(&self.right.to_compressed());
+        bytes
+    }
+
+    pub fn from_compressed(bytes: &[u8]) -> Result<Self, DecodeError> {
+        check_length(bytes, Self::COMPRESSED_BYTES)?;
+        Ok(Self {
+            left: Affine::from_compressed(&bytes[..FIELD_BYTES])?,
+            right: Affine::from_compressed(&bytes[FIELD_BYTES..])?,
+        })
+    }
+
+    pub fn to_hex(&self) -> String {
+        format!("0x{}", hex::encode(self.to_compressed()))
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, DecodeError> {
+        Self::from_compressed(&decode_hex(s)?)
+    }
+}
+
+impl<F: CurveField> Serialize for Affine<F> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de, F: CurveField> Deserialize<'de> for Affine<F> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::from_hex(&s).map_err(de::Error::custom)
+    }
+}
+
+impl<F: CurveField> Serialize for EncryptedBalance<F> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de, F: CurveField> Deserialize<'de> for EncryptedBalance<F> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::from_hex(&s).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn random_point<F: CurveField>() -> Affine<F> {
+        loop {
+            let x = F::random(OsRng);
+            if let Some(y) = Option::<F>::from((x.square() * x + F::curve_b()).sqrt()) {
+                return Affine { x, y };
+            }
+        }
+    }
+
+    fn roundtrip<F: CurveField>() {
+        let value = F::random(OsRng);
+        assert_eq!(field_from_hex::<F>(&field_to_hex(&value)).unwrap(), value);
+
+        for point in [random_point::<F>(), Affine::identity()] {
+            let decoded = Affine::<F>::from_compressed(&point.to_compressed()).unwrap();
+            assert_eq!((decoded.x, decoded.y), (point.x, point.y));
+
+            let decoded = Affine::<F>::from_uncompressed(&point.to_uncompressed()).unwrap();
+            assert_eq!((decoded.x, decoded.y), (point.x, point.y));
+
+            let negated = Affine {
+                x: point.x,
+                y: -point.y,
+            };
+            let decoded = Affine::<F>::from_hex(&negated.to_hex()).unwrap();
+            assert_eq!((decoded.x, decoded.y), (negated.x, negated.y));
+        }
+
+        let balance = EncryptedBalance {
+            left: random_point::<F>(),
+            right: Affine::identity(),
+        };
+        let json = serde_json::to_string(&balance).unwrap();
+        assert_eq!(json, format!("\"{}\"", balance.to_hex()));
+        let decoded: EncryptedBalance<F> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.to_uncompressed(), balance.to_uncompressed());
+    }
+
+    #[test]
+    fn encoding_roundtrip_test() {
+        roundtrip::<Fp>();
+        roundtrip::<Fr>();
+    }
+
+    #[test]
+    fn field_hex_test() {
+        assert_eq!(
+            field_to_hex(&Fr::one()),
+            format!("0x01{}", "00".repeat(FIELD_BYTES - 1))
+        );
+        assert_eq!(
+            field_from_hex::<Fr>("0x01").unwrap_err(),
+            DecodeError::InvalidLength {
+                expected: FIELD_BYTES,
+                actual: 1
+            }
+        );
+    }
+
+    #[test]
+    fn decode_rejects_invalid_test() {
+        let point = random_point::<Fr>();
+
+        let mut off_curve = point.to_uncompressed();
+        off_curve[0] ^= 1;
+        assert_eq!(
+            Affine::<Fr>::from_uncompressed(&off_curve).unwrap_err(),
+            DecodeError::NotOnCurve
+        );
+
+        // The BN254 modulus is below 2^254, so setting bit 254 is never canonical.
+        let mut non_canonical = point.to_compressed();
+        non_canonical[FIELD_BYTES - 1] |= 0x40;
+        assert_eq!(
+            Affine::<Fr>::from_compressed(&non_canonical).unwrap_err(),
+            DecodeError::NonCanonicalField
+        );
+
+        assert_eq!(
+            Affine::<Fr>::from_compressed(&point.to_uncompressed()).unwrap_err(),
+            DecodeError::InvalidLength {
+                expected: 32,
+                actual: 64
+            }
+        );
+        assert!(matches!(
+            EncryptedBalance::<Fr>::from_hex("0xzz"),
+            Err(DecodeError::InvalidHex(_))
+        ));
+    }
+}
@@ -1,78 +1,101 @@
-use ff::Field;
 use halo2_proofs::{
+    arithmetic::FieldExt,
     circuit::{Layouter, SimpleFloorPlanner, Value},
-    plonk::{
-        create_proof, keygen_pk, keygen_vk, verify_proof, Advice, Circuit, Column,
-        ConstraintSystem, Error, Instance,
-    },
-    poly::{
-        commitment::ParamsProver,
-        ipa::{
-            commitment::{IPACommitmentScheme, ParamsIPA},
-            multiopen::ProverIPA,
-            strategy::SingleStrategy,
-        },
-        VerificationStrategy,
-    },
-    transcript::{
-        Blake2bRead, Blake2bWrite, Challenge255, TranscriptReadBuffer, TranscriptWriterBuffer,
-    },
+    plonk::{Advice, Circuit, Column, ConstraintSystem, Error, Instance},
 };
-use halo2curves::pasta::{pallas, vesta, EqAffine, Fp};
 
 use halo2_gadgets::poseidon::{
-    primitives::{self as poseidon, ConstantLength, Spec},
-    Hash, Pow5Chip, Pow5Config,
+    primitives::{self as poseidon, ConstantLength, Domain, Spec},
+    PaddedWord, Pow5Chip, Pow5Config, Sponge,
 };
 use std::convert::TryInto;
 use std::marker::PhantomData;
 
-use rand::rngs::OsRng;
+mod batch;
+pub mod bn256;
+mod chip;
+pub mod encoding;
+pub mod params;
+#[cfg(feature = "poseidon2")]
+pub mod poseidon2;
+mod prover;
+mod sponge;
+pub mod vectors;
+
+pub use batch::{BatchHashCircuit, BatchHashConfig};
+pub use chip::{LeafHashChip, LeafHashConfig, LeafHashInstructions};
+pub use prover::{
+    encode_calldata, AnyLeafHashProver, AnyLeafHashVerifier, Backend, BackendKind, Ipa, KzgEvm,
+    LeafHashProver, LeafHashVerifier, ProverError,
+};
+pub use sponge::{permute, VariableLength};
 
 #[derive(Clone, Copy)]
-struct HashCircuit<S, const WIDTH: usize, const RATE: usize, const L: usize>
+pub struct HashCircuit<F, S, D, const WIDTH: usize, const RATE: usize, const L: usize>
 where
-    S: Spec<Fp, WIDTH, RATE> + Clone + Copy,
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Clone + Copy,
+    D: Domain<F, RATE> + Clone + Copy,
 {
-    message: Value<[Fp; L]>,
+    message: Value<[F; L]>,
     _spec: PhantomData<S>,
+    _domain: PhantomData<D>,
 }
 
 #[derive(Debug, Clone, Copy)]
-pub struct Affine {
-    pub x: Fp,
-    pub y: Fp,
+pub struct Affine<F: FieldExt> {
+    pub x: F,
+    pub y: F,
 }
 
 #[derive(Debug, Clone, Copy)]
-pub struct EncryptedBalance {
-    pub left: Affine,
-    pub right: Affine,
+pub struct EncryptedBalance<F: FieldExt> {
+    pub left: Affine<F>,
+    pub right: Affine<F>,
 }
 
 #[derive(Debug, Clone)]
-struct MyConfig<const WIDTH: usize, const RATE: usize, const L: usize> {
-    input: [Column<Advice>; L],
+pub struct MyConfig<F: FieldExt, const WIDTH: usize, const RATE: usize> {
+    input: [Column<Advice>; RATE],
     expected: Column<Instance>,
-    poseidon_config: Pow5Config<Fp, WIDTH, RATE>,
+    poseidon_config: Pow5Config<F, WIDTH, RATE>,
 }
 
-impl<S, const WIDTH: usize, const RATE: usize, const L: usize> Circuit<Fp>
-    for HashCircuit<S, WIDTH, RATE, L>
+impl<F, S, D, const WIDTH: usize, const RATE: usize, const L: usize>
+    HashCircuit<F, S, D, WIDTH, RATE, L>
 where
-    S: Spec<Fp, WIDTH, RATE> + Copy + Clone,
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Copy + Clone,
+    D: Domain<F, RATE> + Copy + Clone,
 {
-    type Config = MyConfig<WIDTH, RATE, L>;
+    pub fn new(message: [F; L]) -> Self {
+        Self {
+            message: Value::known(message),
+            _spec: PhantomData,
+            _domain: PhantomData,
+        }
+    }
+}
+
+impl<F, S, D, const WIDTH: usize, const RATE: usize, const L: usize> Circuit<F>
+    for HashCircuit<F, S, D, WIDTH, RATE, L>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Copy + Clone,
+    D: Domain<F, RATE> + Copy + Clone,
+{
+    type Config = MyConfig<F, WIDTH, RATE>;
     type FloorPlanner = SimpleFloorPlanner;
 
     fn without_witnesses(&self) -> Self {
         Self {
             message: Value::unknown(),
             _spec: PhantomData,
+            _domain: PhantomData,
         }
     }
 
-    fn configure(meta: &mut ConstraintSystem<Fp>) -> Self::Config {
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
         let state = (0..WIDTH).map(|_| meta.advice_column()).collect::<Vec<_>>();
         let expected = meta.instance_column();
         meta.enable_equality(expected);
@@ -99,10 +122,11 @@ where
     fn synthesize(
         &self,
         config: Self::Config,
-        mut layouter: impl Layouter<Fp>,
+        mut layouter: impl Layouter<F>,
     ) -> Result<(), Error> {
         let chip = Pow5Chip::construct(config.poseidon_config.clone());
 
+        // The message is laid out RATE words per row, so it may be longer than one block.
         let message = layouter.assign_region(
             || "load message",
             |mut region| {
@@ -110,41 +134,57 @@ where
                     let value = self.message.map(|message_vals| message_vals[i]);
                     region.assign_advice(
                         || format!("load message_{}", i),
-                        config.input[i],
-                        0,
+                        config.input[i % RATE],
+                        i / RATE,
                         || value,
                     )
                 };
 
-                let message: Result<Vec<_>, Error> = (0..L).map(message_word).collect();
-                Ok(message?.try_into().unwrap())
+                (0..L).map(message_word).collect::<Result<Vec<_>, Error>>()
             },
         )?;
 
-        let hasher = Hash::<_, _, S, ConstantLength<L>, WIDTH, RATE>::init(
-            chip,
-            layouter.namespace(|| "init"),
-        )?;
-        let output = hasher.hash(layouter.namespace(|| "hash"), message)?;
+        let mut sponge =
+            Sponge::<_, _, S, _, D, WIDTH, RATE>::new(chip, layouter.namespace(|| "init"))?;
+        for (i, word) in message.into_iter().enumerate() {
+            sponge.absorb(
+                layouter.namespace(|| format!("absorb_{}", i)),
+                PaddedWord::Message(word),
+            )?;
+        }
+        for (i, pad) in D::padding(L).into_iter().enumerate() {
+            sponge.absorb(
+                layouter.namespace(|| format!("absorb_padding_{}", i)),
+                PaddedWord::Padding(pad),
+            )?;
+        }
+        let output = sponge
+            .finish_absorbing(layouter.namespace(|| "finish absorbing"))?
+            .squeeze(layouter.namespace(|| "squeeze"))?;
 
         layouter.constrain_instance(output.cell(), config.expected, 0)
     }
 }
 
 #[derive(Debug, Clone, Copy)]
-struct MySpec<const WIDTH: usize, const RATE: usize>;
+pub struct MySpec<const WIDTH: usize, const RATE: usize>;
 
-impl<const WIDTH: usize, const RATE: usize> Spec<Fp, WIDTH, RATE> for MySpec<WIDTH, RATE> {
+impl<F: FieldExt, const WIDTH: usize, const RATE: usize> Spec<F, WIDTH, RATE>
+    for MySpec<WIDTH, RATE>
+{
     fn full_rounds() -> usize {
-        8
+        params::round_numbers(F::NUM_BITS, WIDTH, params::ALPHA, params::SECURITY_BITS).full_rounds
     }
 
     fn partial_rounds() -> usize {
-        56
+        // Pow5Chip constrains partial rounds in pairs, so round up to an even count.
+        let rounds =
+            params::round_numbers(F::NUM_BITS, WIDTH, params::ALPHA, params::SECURITY_BITS);
+        (rounds.partial_rounds + 1) & !1
     }
 
-    fn sbox(val: Fp) -> Fp {
-        val.pow_vartime(&[5])
+    fn sbox(val: F) -> F {
+        val.pow_vartime(&[params::ALPHA])
     }
 
     fn secure_mds() -> usize {
@@ -152,7 +192,45 @@ impl<const WIDTH: usize, const RATE: usize> Spec<Fp, WIDTH, RATE> for MySpec<WID
     }
 }
 
-pub fn leaf_to_message(balance: EncryptedBalance, public_key: Affine, nonce: Fp) -> [Fp; 8] {
+pub type LeafHashCircuit<F> = HashCircuit<F, MySpec<9, 8>, ConstantLength<8>, 9, 8, 8>;
+pub type NodeHashCircuit<F> = HashCircuit<F, MySpec<3, 2>, ConstantLength<2>, 3, 2, 2>;
+
+/// Tags a leaf with the layout it was encoded under and the chain it belongs to.
+///
+/// The header fills the last slot of the leaf message, which used to be zero,
+/// so digests of different versions, chains, or non-leaf 8-element inputs with
+/// a zero in that slot never coincide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeafDomain {
+    pub version: u16,
+    pub chain_id: u64,
+}
+
+impl LeafDomain {
+    pub const TAG: u32 = u32::from_be_bytes(*b"leaf");
+    pub const VERSION: u16 = 1;
+
+    pub fn new(chain_id: u64) -> Self {
+        Self {
+            version: Self::VERSION,
+            chain_id,
+        }
+    }
+
+    /// Packs `TAG || version || chain_id` into a single field element.
+    pub fn header<F: FieldExt>(&self) -> F {
+        F::from_u128(
+            (Self::TAG as u128) << 80 | (self.version as u128) << 64 | self.chain_id as u128,
+        )
+    }
+}
+
+pub fn leaf_to_message<F: FieldExt>(
+    balance: EncryptedBalance<F>,
+    public_key: Affine<F>,
+    nonce: F,
+    domain: LeafDomain,
+) -> [F; 8] {
     let EncryptedBalance { left, right } = balance;
     let (pub_x, pub_y) = (public_key.x, public_key.y);
     [
@@ -163,13 +241,63 @@ pub fn leaf_to_message(balance: EncryptedBalance, public_key: Affine, nonce: Fp)
         pub_x,
         pub_y,
         nonce,
-        Fp::zero(),
+        domain.header(),
     ]
 }
 
+pub fn hash_message<F: FieldExt, const L: usize>(message: [F; L]) -> F {
+    poseidon::Hash::<_, MySpec<9, 8>, ConstantLength<L>, 9, 8>::init().hash(message)
+}
+
+/// Hashes a message of any length with `10*` padding, so that messages of
+/// different lengths never share a padded encoding.
+pub fn hash_message_var<F: FieldExt>(message: &[F]) -> F {
+    sponge::hash::<F, MySpec<9, 8>, VariableLength, 9, 8>(message)
+}
+
+/// Derives an account address from its public key, as `sender_public_key.hash()`
+/// in the design and the `from` of `IRollup.deposit` expect. The two-element
+/// domain keeps addresses apart from leaf digests.
+pub fn address_from_pubkey<F: FieldExt>(public_key: Affine<F>) -> F {
+    hash_message([public_key.x, public_key.y])
+}
+
+/// Hashes two sibling nodes of a Merkle tree with the width-3 permutation.
+pub fn hash_node<F: FieldExt>(left: F, right: F) -> F {
+    poseidon::Hash::<_, MySpec<3, 2>, ConstantLength<2>, 3, 2>::init().hash([left, right])
+}
+
+pub fn hash_leaf<F: FieldExt>(
+    balance: EncryptedBalance<F>,
+    public_key: Affine<F>,
+    nonce: F,
+    domain: LeafDomain,
+) -> F {
+    hash_message(leaf_to_message(balance, public_key, nonce, domain))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ff::Field;
+    use halo2_proofs::{
+        dev::MockProver,
+        plonk::{create_proof, keygen_pk, keygen_vk, verify_proof},
+        poly::{
+            commitment::ParamsProver,
+            ipa::{
+                commitment::{IPACommitmentScheme, ParamsIPA},
+                multiopen::ProverIPA,
+                strategy::SingleStrategy,
+            },
+            VerificationStrategy,
+        },
+        transcript::{
+            Blake2bRead, Blake2bWrite, Challenge255, TranscriptReadBuffer, TranscriptWriterBuffer,
+        },
+    };
+    use halo2curves::pasta::{pallas, vesta, EqAffine, Fp};
+    use rand::rngs::OsRng;
 
     #[test]
     fn poseidon_hash_test() {
@@ -180,10 +308,12 @@ mod tests {
         const RATE: usize = 8;
         const LENGTH: usize = 8;
 
-        let empty_circuit = HashCircuit::<MySpec<9, 8>, WIDTH, RATE, LENGTH> {
-            message: Value::unknown(),
-            _spec: PhantomData,
-        };
+        let empty_circuit =
+            HashCircuit::<Fp, MySpec<9, 8>, ConstantLength<LENGTH>, WIDTH, RATE, LENGTH> {
+                message: Value::unknown(),
+                _spec: PhantomData,
+                _domain: PhantomData,
+            };
 
         let vk = keygen_vk(&params, &empty_circuit).expect("keygen_vk should not fail");
         let pk = keygen_pk(&params, vk, &empty_circuit).expect("keygen_pk should not fail");
@@ -199,9 +329,10 @@ mod tests {
         let output = poseidon::Hash::<_, MySpec<9, 8>, ConstantLength<LENGTH>, WIDTH, RATE>::init()
             .hash(message);
 
-        let circuit = HashCircuit::<MySpec<9, 8>, WIDTH, RATE, LENGTH> {
+        let circuit = HashCircuit::<Fp, MySpec<9, 8>, ConstantLength<LENGTH>, WIDTH, RATE, LENGTH> {
             message: Value::known(message),
             _spec: PhantomData,
+            _domain: PhantomData,
         };
 
         let mut transcript = Blake2bWrite::<_, EqAffine, Challenge255<_>>::init(vec![]);
@@ -230,4 +361,82 @@ mod tests {
         )
         .is_ok())
     }
+
+    #[test]
+    fn hash_leaf_test() {
+        const K: u32 = 7;
+
+        let rng = OsRng;
+        let point = || Affine {
+            x: Fp::random(rng),
+            y: Fp::random(rng),
+        };
+        let balance = EncryptedBalance {
+            left: point(),
+            right: point(),
+        };
+        let public_key = point();
+        let nonce = Fp::random(rng);
+        let domain = LeafDomain::new(1);
+
+        let message = leaf_to_message(balance, public_key, nonce, domain);
+        let output = hash_leaf(balance, public_key, nonce, domain);
+        assert_eq!(output, hash_message(message));
+
+        // The header separates chains, versions and the old zero-padded layout.
+        assert_ne!(
+            output,
+            hash_leaf(balance, public_key, nonce, LeafDomain::new(2))
+        );
+        let mut legacy = message;
+        legacy[7] = Fp::zero();
+        assert_ne!(output, hash_message(legacy));
+        let next = LeafDomain {
+            version: LeafDomain::VERSION + 1,
+            chain_id: 1,
+        };
+        assert_ne!(output, hash_leaf(balance, public_key, nonce, next));
+
+        let circuit = LeafHashCircuit::new(message);
+        let prover = MockProver::run(K, &circuit, vec![vec![output]]).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+    }
+
+    #[test]
+    fn multi_block_hash_test() {
+        const K: u32 = 8;
+        const LENGTH: usize = 20;
+
+        let message: [Fp; LENGTH] = [(); LENGTH].map(|_| Fp::random(OsRng));
+
+        let output = hash_message(message);
+        assert_eq!(
+            output,
+            sponge::hash::<Fp, MySpec<9, 8>, ConstantLength<LENGTH>, 9, 8>(&message)
+        );
+
+        let circuit =
+            HashCircuit::<Fp, MySpec<9, 8>, ConstantLength<LENGTH>, 9, 8, LENGTH>::new(message);
+        let prover = MockProver::run(K, &circuit, vec![vec![output]]).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        let output = hash_message_var(&message);
+        let circuit = HashCircuit::<Fp, MySpec<9, 8>, VariableLength, 9, 8, LENGTH>::new(message);
+        let prover = MockProver::run(K, &circuit, vec![vec![output]]).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+    }
+
+    #[test]
+    fn variable_length_padding_test() {
+        let message: Vec<Fp> = (0..8).map(|_| Fp::random(OsRng)).collect();
+
+        // A trailing zero must not be absorbed into the padding.
+        let mut extended = message.clone();
+        extended.push(Fp::zero());
+        assert_ne!(hash_message_var(&message), hash_message_var(&extended));
+
+        // Same words, different domain.
+        let message: [Fp; 8] = message.try_into().unwrap();
+        assert_ne!(hash_message_var(&message), hash_message(message));
+    }
 }
@@ -0,0 +1,359 @@
+//! Round number derivation and sanity checks for Poseidon specs.
+//!
+//! The round numbers are a search over the bounds in `calc_round_numbers.py`
+//! from the Poseidon reference implementation: the smallest `R_F * t + R_P`
+//! satisfying the statistical, interpolation and Gröbner basis bounds, plus the
+//! recommended security margin of two full rounds and 7.5% more partial rounds.
+
+use halo2_gadgets::poseidon::primitives::Spec;
+use halo2_proofs::arithmetic::FieldExt;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub const ALPHA: u64 = 5;
+pub const SECURITY_BITS: u32 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoundNumbers {
+    pub full_rounds: usize,
+    pub partial_rounds: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpecError {
+    FullRounds { required: usize, actual: usize },
+    PartialRounds { required: usize, actual: usize },
+    OddFullRounds(usize),
+    SboxNotPermutation(u64),
+    SboxMismatch(u64),
+    MdsNotInvertible,
+    NotMds,
+    InvariantSubspace,
+}
+
+static ROUND_NUMBERS: Lazy<Mutex<HashMap<(u32, usize, u64, u32), RoundNumbers>>> =
+    Lazy::new(Default::default);
+
+/// Returns the round numbers for an `x^alpha` Poseidon instance over a field of
+/// `field_bits` bits with `width` state words and `security` bits of security.
+///
+/// Specs ask for their round numbers on every permutation and chip
+/// `configure`, so each search runs once and is cached.
+pub fn round_numbers(field_bits: u32, width: usize, alpha: u64, security: u32) -> RoundNumbers {
+    *ROUND_NUMBERS
+        .lock()
+        .unwrap()
+        .entry((field_bits, width, alpha, security))
+        .or_insert_with(|| search_round_numbers(field_bits, width, alpha, security))
+}
+
+fn search_round_numbers(field_bits: u32, width: usize, alpha: u64, security: u32) -> RoundNumbers {
+    assert!(alpha >= 3 && width >= 2);
+
+    let (n, t, m) = (field_bits as f64, width as f64, security as f64);
+    let log_alpha = |x: f64| x.ln() / (alpha as f64).ln();
+
+    // Smallest number of full rounds (before the margin) for a given R_P.
+    let min_full_rounds = |r_p: f64| {
+        let statistical = if m <= (n - (alpha as f64 - 1.0) / 2.0).floor() * (t + 1.0) {
+            6.0
+        } else {
+            10.0
+        };
+        let interpolation = 1.0 + (log_alpha(2.0) * m.min(n)).ceil() + log_alpha(t).ceil() - r_p;
+        let groebner_1 = log_alpha(2.0) * m.min(n) - r_p;
+        let groebner_2 = t - 1.0 + log_alpha(2.0) * (m / (t + 1.0)).min(n / 2.0) - r_p;
+        let groebner_3 = (t - 2.0 + m / (2.0 * (alpha as f64).log2()) - r_p) / (t - 1.0);
+
+        let r_f = [
+            statistical,
+            interpolation,
+            groebner_1,
+            groebner_2,
+            groebner_3,
+        ]
+        .iter()
+        .fold(4.0f64, |acc, bound| acc.max(bound.ceil()));
+        // Full rounds are split evenly around the partial rounds.
+        (r_f as usize + 1) & !1
+    };
+
+    let mut best: Option<(usize, RoundNumbers)> = None;
+    for r_p in 1..500 {
+        let full_rounds = min_full_rounds(r_p as f64) + 2;
+        let partial_rounds = (r_p as f64 * 1.075).ceil() as usize;
+        let cost = full_rounds * width + partial_rounds;
+
+        let better = match best {
+            None => true,
+            Some((best_cost, best_rounds)) => {
+                cost < best_cost || (cost == best_cost && full_rounds < best_rounds.full_rounds)
+            }
+        };
+        if better {
+            best = Some((
+                cost,
+                RoundNumbers {
+                    full_rounds,
+                    partial_rounds,
+                },
+            ));
+        }
+    }
+
+    best.unwrap().1
+}
+
+/// Checks `S` against the derived round numbers and checks its S-box and MDS
+/// matrix.
+///
+/// The MDS check covers invertibility and the MDS property (every square
+/// submatrix is non-singular). It also rejects matrices with a non-trivial
+/// invariant subspace on which the partial-round S-box is never active. That
+/// is a single rank test on the matrix itself, a quick screen rather than the
+/// full subspace trail search of the reference scripts.
+pub fn validate_spec<F, S, const WIDTH: usize, const RATE: usize>(
+    alpha: u64,
+    security: u32,
+) -> Result<(), SpecError>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+{
+    if gcd(alpha, modulus_minus_one_mod::<F>(alpha)) != 1 {
+        return Err(SpecError::SboxNotPermutation(alpha));
+    }
+    for x in [F::from(2u64), F::from(3u64), -F::from(7u64)] {
+        if S::sbox(x) != x.pow_vartime(&[alpha]) {
+            return Err(SpecError::SboxMismatch(alpha));
+        }
+    }
+
+    let required = round_numbers(F::NUM_BITS, WIDTH, alpha, security);
+    if S::full_rounds() % 2 != 0 {
+        return Err(SpecError::OddFullRounds(S::full_rounds()));
+    }
+    if S::full_rounds() < required.full_rounds {
+        return Err(SpecError::FullRounds {
+            required: required.full_rounds,
+            actual: S::full_rounds(),
+        });
+    }
+    if S::partial_rounds() < required.partial_rounds {
+        return Err(SpecError::PartialRounds {
+            required: required.partial_rounds,
+            actual: S::partial_rounds(),
+        });
+    }
+
+    let (_, mds, mds_inv) = S::constants();
+    check_mds(&mds, &mds_inv)
+}
+
+fn check_mds<F: FieldExt, const WIDTH: usize>(
+    mds: &[[F; WIDTH]; WIDTH],
+    mds_inv: &[[F; WIDTH]; WIDTH],
+) -> Result<(), SpecError> {
+    for i in 0..WIDTH {
+        for j in 0..WIDTH {
+            let entry = (0..WIDTH).fold(F::zero(), |acc, k| acc + mds[i][k] * mds_inv[k][j]);
+            if entry != if i == j { F::one() } else { F::zero() } {
+                return Err(SpecError::MdsNotInvertible);
+            }
+        }
+    }
+
+    if !is_cauchy(mds) && !all_minors_nonzero(mds) {
+        return Err(SpecError::NotMds);
+    }
+
+    if has_invariant_subspace(mds) {
+        return Err(SpecError::InvariantSubspace);
+    }
+
+    Ok(())
+}
+
+/// Rows `e_0 M^i` for `i < WIDTH` span the space iff no non-zero `M`-invariant
+/// subspace keeps the first state word (the only S-box input of a partial
+/// round) at zero.
+fn has_invariant_subspace<F: FieldExt, const WIDTH: usize>(matrix: &[[F; WIDTH]; WIDTH]) -> bool {
+    let mut rows = Vec::with_capacity(WIDTH);
+    let mut row = [F::zero(); WIDTH];
+    row[0] = F::one();
+    for _ in 0..WIDTH {
+        rows.push(row.to_vec());
+        let mut next = [F::zero(); WIDTH];
+        for (j, word) in next.iter_mut().enumerate() {
+            *word = (0..WIDTH).fold(F::zero(), |acc, k| acc + row[k] * matrix[k][j]);
+        }
+        row = next;
+    }
+    rank(rows) != WIDTH
+}
+
+/// A Cauchy matrix `1 / (x_i + y_j)` with distinct `x`s and distinct `y`s is
+/// MDS, which spares us from enumerating every minor.
+fn is_cauchy<F: FieldExt, const WIDTH: usize>(mds: &[[F; WIDTH]; WIDTH]) -> bool {
+    let mut sums = [[F::zero(); WIDTH]; WIDTH];
+    for i in 0..WIDTH {
+        for j in 0..WIDTH {
+            match Option::<F>::from(mds[i][j].invert()) {
+                Some(sum) => sums[i][j] = sum,
+                None => return false,
+            }
+        }
+    }
+
+    for i in 0..WIDTH {
+        for j in 0..WIDTH {
+            if sums[i][j] - sums[i][0] - sums[0][j] + sums[0][0] != F::zero() {
+                return false;
+            }
+        }
+    }
+
+    let distinct = |values: Vec<F>| {
+        values
+            .iter()
+            .enumerate()
+            .all(|(i, a)| values[i + 1..].iter().all(|b| a != b))
+    };
+    distinct((0..WIDTH).map(|i| sums[i][0]).collect())
+        && distinct((0..WIDTH).map(|j| sums[0][j]).collect())
+}
+
+fn all_minors_nonzero<F: FieldExt, const WIDTH: usize>(mds: &[[F; WIDTH]; WIDTH]) -> bool {
+    let subsets: Vec<Vec<usize>> = (1..(1usize << WIDTH))
+        .map(|mask| (0..WIDTH).filter(|i| mask & (1 << i) != 0).collect())
+        .collect();
+
+    subsets.iter().all(|rows| {
+        subsets
+            .iter()
+            .filter(|cols| cols.len() == rows.len())
+            .all(|cols| {
+                let minor = rows
+                    .iter()
+                    .map(|&i| cols.iter().map(|&j| mds[i][j]).collect())
+                    .collect();
+                rank(minor) == rows.len()
+            })
+    })
+}
+
+fn rank<F: FieldExt>(mut rows: Vec<Vec<F>>) -> usize {
+    let width = rows.first().map_or(0, |row| row.len());
+    let mut rank = 0;
+    for col in 0..width {
+        let pivot = match (rank..rows.len()).find(|&i| rows[i][col] != F::zero()) {
+            Some(pivot) => pivot,
+            None => continue,
+        };
+        rows.swap(rank, pivot);
+        let inv = rows[rank][col].invert().unwrap();
+        for i in rank + 1..rows.len() {
+            let factor = rows[i][col] * inv;
+            for j in col..width {
+                let sub = rows[rank][j] * factor;
+                rows[i][j] -= sub;
+            }
+        }
+        rank += 1;
+    }
+    rank
+}
+
+/// `(p - 1) mod m`, reading the little-endian representation of `-1`.
+fn modulus_minus_one_mod<F: FieldExt>(m: u64) -> u64 {
+    (-F::one())
+        .to_repr()
+        .as_ref()
+        .iter()
+        .rev()
+        .fold(0u64, |acc, byte| ((acc << 8) + *byte as u64) % m)
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MySpec;
+    use halo2curves::{bn256::Fr, pasta::Fp};
+
+    #[derive(Debug)]
+    struct ShortSpec;
+
+    impl Spec<Fr, 9, 8> for ShortSpec {
+        fn full_rounds() -> usize {
+            8
+        }
+
+        fn partial_rounds() -> usize {
+            56
+        }
+
+        fn sbox(val: Fr) -> Fr {
+            val.pow_vartime(&[5])
+        }
+
+        fn secure_mds() -> usize {
+            0
+        }
+    }
+
+    #[test]
+    fn round_numbers_test() {
+        // P128Pow5T3 in halo2_gadgets.
+        assert_eq!(
+            round_numbers(255, 3, ALPHA, SECURITY_BITS),
+            RoundNumbers {
+                full_rounds: 8,
+                partial_rounds: 56
+            }
+        );
+        assert_eq!(
+            round_numbers(254, 9, ALPHA, SECURITY_BITS),
+            RoundNumbers {
+                full_rounds: 8,
+                partial_rounds: 57
+            }
+        );
+    }
+
+    #[test]
+    fn validate_spec_test() {
+        assert_eq!(
+            validate_spec::<Fr, MySpec<9, 8>, 9, 8>(ALPHA, SECURITY_BITS),
+            Ok(())
+        );
+        assert_eq!(
+            validate_spec::<Fp, MySpec<9, 8>, 9, 8>(ALPHA, SECURITY_BITS),
+            Ok(())
+        );
+        assert_eq!(
+            validate_spec::<Fr, MySpec<3, 2>, 3, 2>(ALPHA, SECURITY_BITS),
+            Ok(())
+        );
+        assert_eq!(
+            validate_spec::<Fr, ShortSpec, 9, 8>(ALPHA, SECURITY_BITS),
+            Err(SpecError::PartialRounds {
+                required: 57,
+                actual: 56
+            })
+        );
+        // x^3 is not a permutation of BN254 since 3 divides p - 1.
+        assert_eq!(
+            validate_spec::<Fr, MySpec<3, 2>, 3, 2>(3, SECURITY_BITS).unwrap_err(),
+            SpecError::SboxNotPermutation(3)
+        );
+    }
+}
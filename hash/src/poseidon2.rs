@@ -0,0 +1,1103 @@
+//! The Poseidon2 permutation, natively and as a chip.
+//!
+//! Poseidon2 keeps Poseidon's full rounds but replaces the MDS multiplication
+//! of the partial rounds with `M_I = J + diag(d)`, where `J` is the all-ones
+//! matrix, and only adds a round constant to the first word. Each partial round
+//! is then affine in the row's inputs and S-box outputs, so `Poseidon2Chip`
+//! constrains `RATE` partial rounds per row. A permutation starts with one
+//! multiplication by the external matrix.
+//!
+//! The round numbers, round constants and matrices are taken from a
+//! `Poseidon2Spec`, so a spec is selected the same way as a Poseidon spec.
+//! Round constants are drawn from the Grain LFSR as the reference
+//! implementation (HorizenLabs/poseidon2) does. The sponge around the
+//! permutation is the one `halo2_gadgets` uses for Poseidon, with a `Domain`
+//! setting the capacity word and padding.
+//!
+//! - `Poseidon2Bn256T3` is the published BN254 instance at `t = 3` from
+//!   `poseidon2_instance_bn256.rs`: `R_F = 8`, `R_P = 56`,
+//!   `M_E = circ(2, 1, 1)` and `d = (1, 1, 2)`. Its permutation matches the
+//!   reference known-answer vector. A permutation takes as many rows as
+//!   `Pow5Chip` with `MySpec<3, 2>`, but a leaf needs four of them.
+//! - `Poseidon2Bn256T12` absorbs a whole leaf in one block. It follows the
+//!   paper's construction for `t = 12`: `R_F = 8`, `R_P = 57` from
+//!   `params::round_numbers`, `M_E = circ(2, 1, 1) (x) M4` and `d_i = 16^i`.
+//!   The reference implementation publishes no BN254 instance at this width.
+//!   `hash_leaf` and the chip's `LeafHashInstructions` use it, in 17 rows per
+//!   leaf.
+
+use ff::Field;
+use halo2_gadgets::poseidon::{
+    primitives::{ConstantLength, Domain, Spec},
+    PaddedWord,
+};
+use halo2_proofs::{
+    arithmetic::FieldExt,
+    circuit::{AssignedCell, Layouter, Region, Value},
+    plonk::{Advice, Column, ConstraintSystem, Error, Expression, Fixed, Selector, VirtualCells},
+    poly::Rotation,
+};
+use halo2curves::bn256::Fr;
+use std::array;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+
+use crate::{
+    chip::leaf_words, leaf_to_message, Affine, EncryptedBalance, LeafDomain, LeafHashInstructions,
+};
+
+/// A Poseidon spec extended with the diagonal of the internal matrix.
+pub trait Poseidon2Spec<F: FieldExt, const WIDTH: usize, const RATE: usize>:
+    Spec<F, WIDTH, RATE>
+{
+    /// The diagonal `d` of `M_I = J + diag(d)`.
+    fn internal_diagonal() -> [F; WIDTH];
+}
+
+/// The reference Poseidon2 instance over the BN254 scalar field at `t = 3`.
+#[derive(Debug, Clone, Copy)]
+pub struct Poseidon2Bn256T3;
+
+/// A Poseidon2 instance over the BN254 scalar field at `t = 12`, so that a
+/// leaf is absorbed in a single block.
+#[derive(Debug, Clone, Copy)]
+pub struct Poseidon2Bn256T12;
+
+/// The `Spec` of `S` with the Grain LFSR constants `halo2_gadgets` derives
+/// for Poseidon, used only for its round constant stream.
+#[derive(Debug)]
+struct GrainStream<S>(PhantomData<S>);
+
+impl<F, S, const WIDTH: usize, const RATE: usize> Spec<F, WIDTH, RATE> for GrainStream<S>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+{
+    fn full_rounds() -> usize {
+        S::full_rounds()
+    }
+
+    fn partial_rounds() -> usize {
+        S::partial_rounds()
+    }
+
+    fn sbox(val: F) -> F {
+        S::sbox(val)
+    }
+
+    fn secure_mds() -> usize {
+        S::secure_mds()
+    }
+}
+
+/// Round constants of a Poseidon2 spec, one row per round.
+///
+/// The reference implementation seeds the Grain LFSR as Poseidon does and
+/// draws `WIDTH` constants for each full round but a single one for each
+/// partial round, so this reads the Poseidon stream in that order. Partial
+/// rounds keep their constant in the first word.
+fn grain_round_constants<F, S, const WIDTH: usize, const RATE: usize>() -> Vec<[F; WIDTH]>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+{
+    let r_f = S::full_rounds() / 2;
+    let r_p = S::partial_rounds();
+    let (poseidon, _, _) = GrainStream::<S>::constants();
+    let mut stream = poseidon.into_iter().flatten();
+
+    (0..S::full_rounds() + r_p)
+        .map(|round| {
+            let words = if round < r_f || round >= r_f + r_p {
+                WIDTH
+            } else {
+                1
+            };
+            let mut rcs = [F::zero(); WIDTH];
+            for rc in rcs[..words].iter_mut() {
+                *rc = stream.next().unwrap();
+            }
+            rcs
+        })
+        .collect()
+}
+
+impl Spec<Fr, 3, 2> for Poseidon2Bn256T3 {
+    fn full_rounds() -> usize {
+        8
+    }
+
+    fn partial_rounds() -> usize {
+        56
+    }
+
+    fn sbox(val: Fr) -> Fr {
+        val.pow_vartime([5])
+    }
+
+    fn secure_mds() -> usize {
+        0
+    }
+
+    fn constants() -> (Vec<[Fr; 3]>, [[Fr; 3]; 3], [[Fr; 3]; 3]) {
+        let external = [[2u64, 1, 1], [1, 2, 1], [1, 1, 2]].map(|row| row.map(Fr::from));
+        // circ(2, 1, 1)^-1 = circ(3, -1, -1) / 4
+        let quarter = Fr::from(4u64).invert().unwrap();
+        let (on, off) = (Fr::from(3u64) * quarter, -quarter);
+        let external_inv = [[on, off, off], [off, on, off], [off, off, on]];
+
+        (
+            grain_round_constants::<Fr, Self, 3, 2>(),
+            external,
+            external_inv,
+        )
+    }
+}
+
+impl Poseidon2Spec<Fr, 3, 2> for Poseidon2Bn256T3 {
+    fn internal_diagonal() -> [Fr; 3] {
+        [1u64, 1, 2].map(Fr::from)
+    }
+}
+
+// The `M4` block of the external matrix from the Poseidon2 paper, and
+// `8 * M4^-1`.
+const M4: [[i64; 4]; 4] = [[5, 7, 1, 3], [4, 6, 1, 1], [1, 3, 5, 7], [1, 1, 4, 6]];
+const M4_INV_8: [[i64; 4]; 4] = [
+    [-1, 3, -6, 7],
+    [1, -1, 4, -5],
+    [-6, 7, -1, 3],
+    [4, -5, 1, -1],
+];
+
+fn field_from_i64(value: i64) -> Fr {
+    let magnitude = Fr::from(value.unsigned_abs());
+    if value < 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+impl Spec<Fr, 12, 11> for Poseidon2Bn256T12 {
+    fn full_rounds() -> usize {
+        8
+    }
+
+    /// `params::round_numbers(254, 12, 5, 128)`.
+    fn partial_rounds() -> usize {
+        57
+    }
+
+    fn sbox(val: Fr) -> Fr {
+        val.pow_vartime([5])
+    }
+
+    fn secure_mds() -> usize {
+        0
+    }
+
+    fn constants() -> (Vec<[Fr; 12]>, [[Fr; 12]; 12], [[Fr; 12]; 12]) {
+        // M_E = circ(2, 1, 1) (x) M4, with inverse circ(3, -1, -1) / 4 (x) M4^-1.
+        let kronecker = |on: i64, off: i64, m4: [[i64; 4]; 4]| -> [[Fr; 12]; 12] {
+            array::from_fn(|i| {
+                array::from_fn(|j| {
+                    let block = if i / 4 == j / 4 { on } else { off };
+                    field_from_i64(block * m4[i % 4][j % 4])
+                })
+            })
+        };
+        let inv_32 = Fr::from(32u64).invert().unwrap();
+        let external = kronecker(2, 1, M4);
+        let external_inv = kronecker(3, -1, M4_INV_8).map(|row| row.map(|entry| entry * inv_32));
+
+        (
+            grain_round_constants::<Fr, Self, 12, 11>(),
+            external,
+            external_inv,
+        )
+    }
+}
+
+impl Poseidon2Spec<Fr, 12, 11> for Poseidon2Bn256T12 {
+    /// `d_i = 16^i`, the first `d_i = s^i` for which the minimal polynomial
+    /// of `M_I^k` is irreducible of degree 12 for `k = 1..=24`, as the
+    /// reference parameter script requires.
+    fn internal_diagonal() -> [Fr; 12] {
+        array::from_fn(|i| Fr::from(16u64).pow_vartime([i as u64]))
+    }
+}
+
+fn external_layer<F: FieldExt, const WIDTH: usize>(
+    state: &mut [F; WIDTH],
+    matrix: &[[F; WIDTH]; WIDTH],
+) {
+    let mut new_state = [F::zero(); WIDTH];
+    for (new_word, row) in new_state.iter_mut().zip(matrix.iter()) {
+        *new_word = row
+            .iter()
+            .zip(state.iter())
+            .fold(F::zero(), |acc, (m, word)| acc + m * word);
+    }
+    *state = new_state;
+}
+
+fn internal_layer<F: FieldExt, const WIDTH: usize>(state: &mut [F; WIDTH], diagonal: &[F; WIDTH]) {
+    let sum = state.iter().fold(F::zero(), |acc, word| acc + word);
+    for (word, d) in state.iter_mut().zip(diagonal.iter()) {
+        *word = *word * d + sum;
+    }
+}
+
+/// Native Poseidon2 permutation, matching the one constrained by `Poseidon2Chip`.
+///
+/// Partial round `r` uses the first word of `round_constants[R_F / 2 + r]`.
+pub fn permute<F, S, const WIDTH: usize, const RATE: usize>(
+    state: &mut [F; WIDTH],
+    external: &[[F; WIDTH]; WIDTH],
+    internal: &[F; WIDTH],
+    round_constants: &[[F; WIDTH]],
+) where
+    F: FieldExt,
+    S: Poseidon2Spec<F, WIDTH, RATE>,
+{
+    let r_f = S::full_rounds() / 2;
+    let r_p = S::partial_rounds();
+
+    external_layer(state, external);
+    for (round, rcs) in round_constants.iter().enumerate() {
+        if round < r_f || round >= r_f + r_p {
+            for (word, rc) in state.iter_mut().zip(rcs.iter()) {
+                *word = S::sbox(*word + rc);
+            }
+            external_layer(state, external);
+        } else {
+            state[0] = S::sbox(state[0] + rcs[0]);
+            internal_layer(state, internal);
+        }
+    }
+}
+
+/// Absorbs `message` and its domain padding RATE words at a time, permuting
+/// after each block, and squeezes a single word.
+pub fn hash<F, S, D, const WIDTH: usize, const RATE: usize>(message: &[F]) -> F
+where
+    F: FieldExt,
+    S: Poseidon2Spec<F, WIDTH, RATE>,
+    D: Domain<F, RATE>,
+{
+    let (round_constants, external, _) = S::constants();
+    let internal = S::internal_diagonal();
+    let mut state = [F::zero(); WIDTH];
+    state[RATE] = D::initial_capacity_element();
+
+    let padded: Vec<F> = message
+        .iter()
+        .copied()
+        .chain(D::padding(message.len()))
+        .collect();
+    assert_eq!(padded.len() % RATE, 0);
+
+    for block in padded.chunks(RATE) {
+        for (word, value) in state.iter_mut().zip(block.iter()) {
+            *word += value;
+        }
+        permute::<F, S, WIDTH, RATE>(&mut state, &external, &internal, &round_constants);
+    }
+
+    state[0]
+}
+
+/// `hash_leaf` with `Poseidon2Bn256T12` in place of Poseidon, absorbing the
+/// eight leaf words in a single block.
+pub fn hash_leaf(
+    balance: EncryptedBalance<Fr>,
+    public_key: Affine<Fr>,
+    nonce: Fr,
+    domain: LeafDomain,
+) -> Fr {
+    hash::<Fr, Poseidon2Bn256T12, ConstantLength<8>, 12, 11>(&leaf_to_message(
+        balance, public_key, nonce, domain,
+    ))
+}
+
+#[derive(Debug, Clone)]
+pub struct Poseidon2Config<F: FieldExt, const WIDTH: usize, const RATE: usize> {
+    state: [Column<Advice>; WIDTH],
+    partial_sbox: [Column<Advice>; RATE],
+    rc: [Column<Fixed>; WIDTH],
+    s_init: Selector,
+    s_absorb: Selector,
+    s_full: Selector,
+    s_partial: Selector,
+    s_partial_single: Selector,
+    round_constants: Vec<[F; WIDTH]>,
+    external: [[F; WIDTH]; WIDTH],
+    internal: [F; WIDTH],
+}
+
+/// Constrains Poseidon2 sponge hashes, one region per absorbed block.
+///
+/// Row 0 of a region holds the incoming state and, in `partial_sbox`, the
+/// block being absorbed. Full rounds take a row each and partial rounds are
+/// packed `RATE` to a row, with the S-box outputs in `partial_sbox`; leftover
+/// partial rounds take a row each.
+#[derive(Debug, Clone)]
+pub struct Poseidon2Chip<
+    F: FieldExt,
+    S: Poseidon2Spec<F, WIDTH, RATE>,
+    const WIDTH: usize,
+    const RATE: usize,
+> {
+    config: Poseidon2Config<F, WIDTH, RATE>,
+    _spec: PhantomData<S>,
+}
+
+fn pow5<F: FieldExt>(x: Expression<F>) -> Expression<F> {
+    let x2 = x.clone() * x.clone();
+    x2.clone() * x2 * x
+}
+
+/// `sum_i coeffs[i] * vars[i]`, skipping zero coefficients.
+fn linear_combination<F: FieldExt>(coeffs: &[F], vars: &[Expression<F>]) -> Expression<F> {
+    coeffs
+        .iter()
+        .zip(vars.iter())
+        .filter(|(coeff, _)| **coeff != F::zero())
+        .fold(Expression::Constant(F::zero()), |acc, (coeff, var)| {
+            acc + var.clone() * Expression::Constant(*coeff)
+        })
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize> Poseidon2Chip<F, S, WIDTH, RATE>
+where
+    F: FieldExt,
+    S: Poseidon2Spec<F, WIDTH, RATE>,
+{
+    /// Configures the permutation gates and enables `rc[0]` as a constant
+    /// column for fixed padding.
+    ///
+    /// The S-box is constrained as `x^5`.
+    pub fn configure(
+        meta: &mut ConstraintSystem<F>,
+        state: [Column<Advice>; WIDTH],
+        partial_sbox: [Column<Advice>; RATE],
+        rc: [Column<Fixed>; WIDTH],
+    ) -> Poseidon2Config<F, WIDTH, RATE> {
+        assert!(RATE < WIDTH);
+        let (round_constants, external, _) = S::constants();
+        let internal = S::internal_diagonal();
+
+        for column in state.iter().chain(partial_sbox.iter()) {
+            meta.enable_equality(*column);
+        }
+        meta.enable_constant(rc[0]);
+
+        let s_init = meta.selector();
+        let s_absorb = meta.selector();
+        let s_full = meta.selector();
+        let s_partial = meta.selector();
+        let s_partial_single = meta.selector();
+
+        // The state a hash starts from is fixed per region rather than copied
+        // from the constants column.
+        meta.create_gate("poseidon2 initial state", |meta| {
+            let s_init = meta.query_selector(s_init);
+            (0..WIDTH)
+                .map(|j| {
+                    s_init.clone()
+                        * (meta.query_advice(state[j], Rotation::cur())
+                            - meta.query_fixed(rc[j], Rotation::cur()))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        meta.create_gate("poseidon2 absorb", |meta| {
+            let s_absorb = meta.query_selector(s_absorb);
+            let words: Vec<_> = (0..WIDTH)
+                .map(|j| {
+                    let word = meta.query_advice(state[j], Rotation::cur());
+                    if j < RATE {
+                        word + meta.query_advice(partial_sbox[j], Rotation::cur())
+                    } else {
+                        word
+                    }
+                })
+                .collect();
+
+            (0..WIDTH)
+                .map(|i| {
+                    let next = meta.query_advice(state[i], Rotation::next());
+                    s_absorb.clone() * (linear_combination(&external[i], &words) - next)
+                })
+                .collect::<Vec<_>>()
+        });
+
+        meta.create_gate("poseidon2 full round", |meta| {
+            let s_full = meta.query_selector(s_full);
+            let words: Vec<_> = (0..WIDTH)
+                .map(|j| {
+                    pow5(
+                        meta.query_advice(state[j], Rotation::cur())
+                            + meta.query_fixed(rc[j], Rotation::cur()),
+                    )
+                })
+                .collect();
+
+            (0..WIDTH)
+                .map(|i| {
+                    let next = meta.query_advice(state[i], Rotation::next());
+                    s_full.clone() * (linear_combination(&external[i], &words) - next)
+                })
+                .collect::<Vec<_>>()
+        });
+
+        meta.create_gate("poseidon2 partial rounds", |meta| {
+            Self::partial_rounds_gate(meta, s_partial, RATE, &state, &partial_sbox, &rc, &internal)
+        });
+        meta.create_gate("poseidon2 partial round", |meta| {
+            Self::partial_rounds_gate(
+                meta,
+                s_partial_single,
+                1,
+                &state,
+                &partial_sbox,
+                &rc,
+                &internal,
+            )
+        });
+
+        Poseidon2Config {
+            state,
+            partial_sbox,
+            rc,
+            s_init,
+            s_absorb,
+            s_full,
+            s_partial,
+            s_partial_single,
+            round_constants,
+            external,
+            internal,
+        }
+    }
+
+    /// Constrains `rounds` consecutive partial rounds within one row.
+    ///
+    /// Partial round `k` adds `rc[k]` to the first word and witnesses its
+    /// S-box output in `partial_sbox[k]`. Every word after the round is affine
+    /// in the row's state, S-box outputs and constants, so it is tracked as a
+    /// coefficient vector over those cells rather than as a nested expression.
+    fn partial_rounds_gate(
+        meta: &mut VirtualCells<'_, F>,
+        selector: Selector,
+        rounds: usize,
+        state: &[Column<Advice>; WIDTH],
+        partial_sbox: &[Column<Advice>; RATE],
+        rc: &[Column<Fixed>; WIDTH],
+        internal: &[F; WIDTH],
+    ) -> Vec<Expression<F>> {
+        let selector = meta.query_selector(selector);
+        let vars: Vec<Expression<F>> = state
+            .iter()
+            .map(|column| meta.query_advice(*column, Rotation::cur()))
+            .chain(
+                partial_sbox[..rounds]
+                    .iter()
+                    .map(|column| meta.query_advice(*column, Rotation::cur())),
+            )
+            .chain(
+                rc[..rounds]
+                    .iter()
+                    .map(|column| meta.query_fixed(*column, Rotation::cur())),
+            )
+            .collect();
+
+        let unit = |index: usize| {
+            let mut coeffs = vec![F::zero(); vars.len()];
+            coeffs[index] = F::one();
+            coeffs
+        };
+        let mut words: Vec<Vec<F>> = (0..WIDTH).map(unit).collect();
+
+        let mut constraints = Vec::with_capacity(rounds + WIDTH);
+        for k in 0..rounds {
+            let mut input = words[0].clone();
+            input[WIDTH + rounds + k] += F::one();
+            constraints.push(
+                selector.clone()
+                    * (vars[WIDTH + k].clone() - pow5(linear_combination(&input, &vars))),
+            );
+
+            words[0] = unit(WIDTH + k);
+            let sum: Vec<F> = (0..vars.len())
+                .map(|v| words.iter().fold(F::zero(), |acc, word| acc + word[v]))
+                .collect();
+            for (word, d) in words.iter_mut().zip(internal.iter()) {
+                for (coeff, s) in word.iter_mut().zip(sum.iter()) {
+                    *coeff = *coeff * d + s;
+                }
+            }
+        }
+
+        for (i, word) in words.iter().enumerate() {
+            let next = meta.query_advice(state[i], Rotation::next());
+            constraints.push(selector.clone() * (linear_combination(word, &vars) - next));
+        }
+        constraints
+    }
+
+    pub fn construct(config: Poseidon2Config<F, WIDTH, RATE>) -> Self {
+        Self {
+            config,
+            _spec: PhantomData,
+        }
+    }
+
+    /// Rows taken by the region of one absorbed block.
+    pub fn permutation_rows() -> usize {
+        let r_p = S::partial_rounds();
+        1 + S::full_rounds() + r_p / RATE + r_p % RATE + 1
+    }
+
+    /// Returns the cell holding the hash of `message` under domain `D`.
+    pub fn hash<D: Domain<F, RATE>>(
+        &self,
+        mut layouter: impl Layouter<F>,
+        message: Vec<PaddedWord<F>>,
+    ) -> Result<AssignedCell<F, F>, Error> {
+        let mut initial = [F::zero(); WIDTH];
+        initial[RATE] = D::initial_capacity_element();
+
+        let input_len = message.len();
+        let words: Vec<PaddedWord<F>> = message
+            .into_iter()
+            .chain(D::padding(input_len).into_iter().map(PaddedWord::Padding))
+            .collect();
+        assert_eq!(words.len() % RATE, 0);
+
+        let mut state: Option<Vec<AssignedCell<F, F>>> = None;
+        for (i, block) in words.chunks(RATE).enumerate() {
+            let next = layouter.assign_region(
+                || format!("poseidon2 block {}", i),
+                |mut region| self.permute_block(&mut region, &initial, state.as_deref(), block),
+            )?;
+            state = Some(next);
+        }
+
+        Ok(state.unwrap()[0].clone())
+    }
+
+    /// Returns the cell holding the digest of the assigned leaf under `S`,
+    /// which is `poseidon2::hash_leaf` for `Poseidon2Bn256T12`.
+    pub fn hash_leaf(
+        &self,
+        layouter: impl Layouter<F>,
+        balance: &[AssignedCell<F, F>; 4],
+        public_key: &[AssignedCell<F, F>; 2],
+        nonce: &AssignedCell<F, F>,
+        domain: LeafDomain,
+    ) -> Result<AssignedCell<F, F>, Error> {
+        self.hash::<ConstantLength<8>>(layouter, leaf_words(balance, public_key, nonce, domain))
+    }
+
+    fn permute_block(
+        &self,
+        region: &mut Region<'_, F>,
+        initial: &[F; WIDTH],
+        previous: Option<&[AssignedCell<F, F>]>,
+        block: &[PaddedWord<F>],
+    ) -> Result<Vec<AssignedCell<F, F>>, Error> {
+        let config = &self.config;
+        let r_f = S::full_rounds() / 2;
+        let r_p = S::partial_rounds();
+
+        config.s_absorb.enable(region, 0)?;
+        let state = match previous {
+            Some(cells) => cells
+                .iter()
+                .enumerate()
+                .map(|(j, cell)| {
+                    cell.copy_advice(|| format!("state_{}", j), region, config.state[j], 0)
+                })
+                .collect::<Result<Vec<_>, Error>>()?,
+            None => {
+                config.s_init.enable(region, 0)?;
+                for (j, word) in initial.iter().enumerate() {
+                    region.assign_fixed(
+                        || format!("initial_{}", j),
+                        config.rc[j],
+                        0,
+                        || Value::known(*word),
+                    )?;
+                }
+                self.assign_state(region, 0, Value::known(*initial))?
+            }
+        };
+        let input = block
+            .iter()
+            .enumerate()
+            .map(|(j, word)| match word {
+                PaddedWord::Message(cell) => {
+                    cell.copy_advice(|| format!("input_{}", j), region, config.partial_sbox[j], 0)
+                }
+                PaddedWord::Padding(value) => region.assign_advice_from_constant(
+                    || format!("input_{}", j),
+                    config.partial_sbox[j],
+                    0,
+                    *value,
+                ),
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let state: Value<Vec<F>> = state.iter().map(|cell| cell.value().copied()).collect();
+        let input: Value<Vec<F>> = input.iter().map(|cell| cell.value().copied()).collect();
+        let mut state = state.zip(input).map(|(state, input)| {
+            let mut state: [F; WIDTH] = state.try_into().unwrap();
+            for (word, value) in state.iter_mut().zip(input.iter()) {
+                *word += value;
+            }
+            external_layer(&mut state, &config.external);
+            state
+        });
+
+        let mut row = 1;
+        let mut cells = self.assign_state(region, row, state)?;
+        for round in 0..S::full_rounds() {
+            if round == r_f {
+                let constants: Vec<F> = config.round_constants[r_f..r_f + r_p]
+                    .iter()
+                    .map(|rcs| rcs[0])
+                    .collect();
+                // Leftover partial rounds are constrained one per row.
+                for chunk in constants.chunks(RATE) {
+                    let rows: Vec<&[F]> = if chunk.len() == RATE {
+                        vec![chunk]
+                    } else {
+                        chunk.chunks(1).collect()
+                    };
+                    for rounds in rows {
+                        state = self.partial_rounds(region, row, rounds, state)?;
+                        row += 1;
+                        cells = self.assign_state(region, row, state)?;
+                    }
+                }
+            }
+
+            let rcs = if round < r_f {
+                config.round_constants[round]
+            } else {
+                config.round_constants[round + r_p]
+            };
+            state = self.full_round(region, row, &rcs, state)?;
+            row += 1;
+            cells = self.assign_state(region, row, state)?;
+        }
+
+        Ok(cells)
+    }
+
+    fn assign_state(
+        &self,
+        region: &mut Region<'_, F>,
+        row: usize,
+        state: Value<[F; WIDTH]>,
+    ) -> Result<Vec<AssignedCell<F, F>>, Error> {
+        (0..WIDTH)
+            .map(|j| {
+                region.assign_advice(
+                    || format!("state_{}", j),
+                    self.config.state[j],
+                    row,
+                    || state.map(|state| state[j]),
+                )
+            })
+            .collect()
+    }
+
+    fn full_round(
+        &self,
+        region: &mut Region<'_, F>,
+        row: usize,
+        rcs: &[F; WIDTH],
+        state: Value<[F; WIDTH]>,
+    ) -> Result<Value<[F; WIDTH]>, Error> {
+        self.config.s_full.enable(region, row)?;
+        for (j, rc) in rcs.iter().enumerate() {
+            region.assign_fixed(
+                || format!("rc_{}", j),
+                self.config.rc[j],
+                row,
+                || Value::known(*rc),
+            )?;
+        }
+
+        Ok(state.map(|mut state| {
+            for (word, rc) in state.iter_mut().zip(rcs.iter()) {
+                *word = S::sbox(*word + rc);
+            }
+            external_layer(&mut state, &self.config.external);
+            state
+        }))
+    }
+
+    fn partial_rounds(
+        &self,
+        region: &mut Region<'_, F>,
+        row: usize,
+        constants: &[F],
+        state: Value<[F; WIDTH]>,
+    ) -> Result<Value<[F; WIDTH]>, Error> {
+        let selector = if constants.len() == RATE {
+            self.config.s_partial
+        } else {
+            assert_eq!(constants.len(), 1);
+            self.config.s_partial_single
+        };
+        selector.enable(region, row)?;
+
+        let next = state.map(|mut state| {
+            let mut sbox_outputs = Vec::with_capacity(constants.len());
+            for rc in constants.iter() {
+                state[0] = S::sbox(state[0] + rc);
+                sbox_outputs.push(state[0]);
+                internal_layer(&mut state, &self.config.internal);
+            }
+            (state, sbox_outputs)
+        });
+
+        for (k, rc) in constants.iter().enumerate() {
+            region.assign_fixed(
+                || format!("rc_{}", k),
+                self.config.rc[k],
+                row,
+                || Value::known(*rc),
+            )?;
+            region.assign_advice(
+                || format!("partial_sbox_{}", k),
+                self.config.partial_sbox[k],
+                row,
+                || next.as_ref().map(|(_, sbox_outputs)| sbox_outputs[k]),
+            )?;
+        }
+
+        Ok(next.map(|(state, _)| state))
+    }
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize> LeafHashInstructions<F>
+    for Poseidon2Chip<F, S, WIDTH, RATE>
+where
+    F: FieldExt,
+    S: Poseidon2Spec<F, WIDTH, RATE>,
+{
+    type Config = Poseidon2Config<F, WIDTH, RATE>;
+
+    fn configure_columns(meta: &mut ConstraintSystem<F>) -> (Self::Config, [Column<Advice>; 7]) {
+        assert!(RATE >= 8, "a leaf is absorbed in a single block");
+        let state = [(); WIDTH].map(|_| meta.advice_column());
+        let partial_sbox = [(); RATE].map(|_| meta.advice_column());
+        let rc = [(); WIDTH].map(|_| meta.fixed_column());
+
+        let config = Self::configure(meta, state, partial_sbox, rc);
+        (config, state[..7].try_into().unwrap())
+    }
+
+    fn construct(config: Self::Config) -> Self {
+        Self::construct(config)
+    }
+
+    fn hash_leaf(
+        &self,
+        layouter: impl Layouter<F>,
+        balance: &[AssignedCell<F, F>; 4],
+        public_key: &[AssignedCell<F, F>; 2],
+        nonce: &AssignedCell<F, F>,
+        domain: LeafDomain,
+    ) -> Result<AssignedCell<F, F>, Error> {
+        Self::hash_leaf(self, layouter, balance, public_key, nonce, domain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MySpec;
+    use ff::PrimeField;
+    use halo2_proofs::{
+        circuit::SimpleFloorPlanner,
+        dev::MockProver,
+        plonk::{Circuit, Instance},
+    };
+    use rand::rngs::OsRng;
+
+    const N: usize = 2;
+    const DOMAIN: LeafDomain = LeafDomain {
+        version: LeafDomain::VERSION,
+        chain_id: 1,
+    };
+
+    fn assert_inverse<const WIDTH: usize>(
+        matrix: &[[Fr; WIDTH]; WIDTH],
+        inverse: &[[Fr; WIDTH]; WIDTH],
+    ) {
+        for (i, row) in matrix.iter().enumerate() {
+            for j in 0..WIDTH {
+                let entry = (0..WIDTH).fold(Fr::zero(), |acc, k| acc + row[k] * inverse[k][j]);
+                assert_eq!(entry, if i == j { Fr::one() } else { Fr::zero() });
+            }
+        }
+    }
+
+    fn decimal(s: &str) -> Fr {
+        Fr::from_str_vartime(s).unwrap()
+    }
+
+    #[test]
+    fn reference_vector_test() {
+        let (round_constants, external, external_inv) = Poseidon2Bn256T3::constants();
+        assert_eq!(round_constants.len(), 64);
+        assert_inverse(&external, &external_inv);
+
+        // First constants of `RC3` in the reference implementation.
+        assert_eq!(
+            round_constants[0][0],
+            decimal(
+                "13128406282895484157369354038809433636203389051939936481821261911791933663254"
+            )
+        );
+        assert_eq!(
+            round_constants[4],
+            [
+                decimal(
+                    "11811415718957691261673974625780511541635150909919309658375768251762566747317"
+                ),
+                Fr::zero(),
+                Fr::zero(),
+            ]
+        );
+
+        // `test_perm_consistency` of the reference implementation.
+        let mut state = [0u64, 1, 2].map(Fr::from);
+        permute::<Fr, Poseidon2Bn256T3, 3, 2>(
+            &mut state,
+            &external,
+            &Poseidon2Bn256T3::internal_diagonal(),
+            &round_constants,
+        );
+        assert_eq!(
+            state,
+            [
+                "5297208644449048816064511434384511824916970985131888684874823260532015509555",
+                "21816030159894113985964609355246484851575571273661473159848781012394295965040",
+                "13940986381491601233448981668101586453321811870310341844570924906201623195336",
+            ]
+            .map(decimal)
+        );
+    }
+
+    #[test]
+    fn t12_vector_test() {
+        let (round_constants, external, external_inv) = Poseidon2Bn256T12::constants();
+        assert_eq!(round_constants.len(), 65);
+        assert_inverse(&external, &external_inv);
+
+        // Computed with an independent model of this instance; the reference
+        // implementation publishes no BN254 instance at `t = 12`.
+        let mut state = array::from_fn(|i| Fr::from(i as u64));
+        permute::<Fr, Poseidon2Bn256T12, 12, 11>(
+            &mut state,
+            &external,
+            &Poseidon2Bn256T12::internal_diagonal(),
+            &round_constants,
+        );
+        assert_eq!(
+            state,
+            [
+                "252562932588223127872071399665654966387100450494081344336404940949745182993",
+                "21816310201322693733061541635245050652110295182204738428699096194286804930286",
+                "20348132104041751560669365375961319213509944145038308117752214449549321094611",
+                "20381726686833343860789182458473472799541729461155202769372163287223924167850",
+                "4306564454801175658662647926138537172229815221989577399349271715588147134050",
+                "2134485362648084855889979723886312008631658974493028098090510556494052388415",
+                "13751196524711656702427336357205413984015301981077454268929957739700325316438",
+                "7893050008776986036752697841991521131915694192413492243322744994534780389776",
+                "15793901776776824050634180065845440424843658321550291474563397106140006811084",
+                "17731858663987749432602965780105141803964849880142477648988064600675746918694",
+                "10380560081607276718893639975726759804702444563209893418630740019941986710564",
+                "21102999027707326929538134457480906656094244841131257813675121644161956749178",
+            ]
+            .map(decimal)
+        );
+    }
+
+    /// Hashes `N` leaves with `Poseidon2Chip`.
+    #[derive(Clone)]
+    struct LeavesCircuit<S, const WIDTH: usize, const RATE: usize> {
+        messages: Value<[[Fr; 8]; N]>,
+        _spec: PhantomData<S>,
+    }
+
+    impl<S, const WIDTH: usize, const RATE: usize> Circuit<Fr> for LeavesCircuit<S, WIDTH, RATE>
+    where
+        S: Poseidon2Spec<Fr, WIDTH, RATE> + Clone,
+    {
+        type Config = (
+            Poseidon2Config<Fr, WIDTH, RATE>,
+            [Column<Advice>; WIDTH],
+            Column<Instance>,
+        );
+        type FloorPlanner = SimpleFloorPlanner;
+
+        fn without_witnesses(&self) -> Self {
+            Self {
+                messages: Value::unknown(),
+                _spec: PhantomData,
+            }
+        }
+
+        fn configure(meta: &mut ConstraintSystem<Fr>) -> Self::Config {
+            let state = [(); WIDTH].map(|_| meta.advice_column());
+            let partial_sbox = [(); RATE].map(|_| meta.advice_column());
+            let rc = [(); WIDTH].map(|_| meta.fixed_column());
+            let digests = meta.instance_column();
+            meta.enable_equality(digests);
+
+            let config =
+                Poseidon2Chip::<Fr, S, WIDTH, RATE>::configure(meta, state, partial_sbox, rc);
+            (config, state, digests)
+        }
+
+        fn synthesize(
+            &self,
+            (config, state, digests): Self::Config,
+            mut layouter: impl Layouter<Fr>,
+        ) -> Result<(), Error> {
+            let chip = Poseidon2Chip::<Fr, S, WIDTH, RATE>::construct(config);
+            for i in 0..N {
+                let cells = layouter.assign_region(
+                    || format!("load leaf {}", i),
+                    |mut region| {
+                        (0..7)
+                            .map(|j| {
+                                region.assign_advice(
+                                    || format!("leaf_{}_{}", i, j),
+                                    state[j % WIDTH],
+                                    j / WIDTH,
+                                    || self.messages.map(|messages| messages[i][j]),
+                                )
+                            })
+                            .collect::<Result<Vec<_>, Error>>()
+                    },
+                )?;
+                let balance = cells[..4].to_vec().try_into().unwrap();
+                let public_key = cells[4..6].to_vec().try_into().unwrap();
+
+                let digest = chip.hash_leaf(
+                    layouter.namespace(|| format!("hash leaf {}", i)),
+                    &balance,
+                    &public_key,
+                    &cells[6],
+                    DOMAIN,
+                )?;
+                layouter.constrain_instance(digest.cell(), digests, i)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Proves the digests of `N` random leaves under `S` and checks that a
+    /// wrong digest is rejected.
+    fn leaves_test<S, const WIDTH: usize, const RATE: usize>(k: u32)
+    where
+        S: Poseidon2Spec<Fr, WIDTH, RATE> + Clone,
+    {
+        let messages = [(); N].map(|_| {
+            let point = || Affine {
+                x: Fr::random(OsRng),
+                y: Fr::random(OsRng),
+            };
+            let balance = EncryptedBalance {
+                left: point(),
+                right: point(),
+            };
+            leaf_to_message(balance, point(), Fr::random(OsRng), DOMAIN)
+        });
+        let circuit = LeavesCircuit::<S, WIDTH, RATE> {
+            messages: Value::known(messages),
+            _spec: PhantomData,
+        };
+        let digests: Vec<Fr> = messages
+            .iter()
+            .map(|message| hash::<Fr, S, ConstantLength<8>, WIDTH, RATE>(message))
+            .collect();
+
+        let prover = MockProver::run(k, &circuit, vec![digests.clone()]).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        let mut wrong = digests;
+        wrong[0] += Fr::one();
+        let prover = MockProver::run(k, &circuit, vec![wrong]).unwrap();
+        assert!(prover.verify().is_err());
+    }
+
+    #[test]
+    fn poseidon2_chip_test() {
+        // Four blocks of `permutation_rows` per leaf.
+        leaves_test::<Poseidon2Bn256T3, 3, 2>(11);
+        // A single block per leaf.
+        leaves_test::<Poseidon2Bn256T12, 12, 11>(7);
+    }
+
+    #[test]
+    fn poseidon2_hash_leaf_test() {
+        let point = || Affine {
+            x: Fr::random(OsRng),
+            y: Fr::random(OsRng),
+        };
+        let balance = EncryptedBalance {
+            left: point(),
+            right: point(),
+        };
+        let (public_key, nonce) = (point(), Fr::random(OsRng));
+        assert_eq!(
+            hash_leaf(balance, public_key, nonce, DOMAIN),
+            hash::<Fr, Poseidon2Bn256T12, ConstantLength<8>, 12, 11>(&leaf_to_message(
+                balance, public_key, nonce, DOMAIN
+            ))
+        );
+    }
+
+    #[test]
+    fn poseidon2_rows_test() {
+        // The absorb row, 8 full rounds, 56 partial rounds packed two to a
+        // row and the output row. Pow5Chip takes a row per full round, one
+        // per two partial rounds and one for the initial state.
+        type T3 = Poseidon2Chip<Fr, Poseidon2Bn256T3, 3, 2>;
+        assert_eq!(T3::permutation_rows(), 38);
+        type Pow5T3 = MySpec<3, 2>;
+        let pow5_rows = <Pow5T3 as Spec<Fr, 3, 2>>::full_rounds()
+            + <Pow5T3 as Spec<Fr, 3, 2>>::partial_rounds() / 2
+            + 1;
+        assert!(T3::permutation_rows() <= pow5_rows);
+    }
+
+    #[test]
+    fn rows_per_leaf_test() {
+        // A leaf is eight words. At `t = 3` Poseidon2 absorbs them in four
+        // blocks; at `t = 12` in one, as does Pow5 at `t = 9`.
+        type T3 = Poseidon2Chip<Fr, Poseidon2Bn256T3, 3, 2>;
+        type T12 = Poseidon2Chip<Fr, Poseidon2Bn256T12, 12, 11>;
+        type Pow5T9 = MySpec<9, 8>;
+        let t3_rows = 4 * T3::permutation_rows();
+        // 1 + 8 + 57 / 11 + 57 % 11 + 1
+        let t12_rows = T12::permutation_rows();
+        assert_eq!(t12_rows, 17);
+        let pow5_rows = <Pow5T9 as Spec<Fr, 9, 8>>::full_rounds()
+            + <Pow5T9 as Spec<Fr, 9, 8>>::partial_rounds() / 2
+            + 1;
+
+        assert!(t12_rows < pow5_rows);
+        assert!(t12_rows < t3_rows);
+    }
+}
@@ -0,0 +1,517 @@
+use ff::Field;
+use halo2_proofs::{
+    arithmetic::{CurveAffine, FieldExt},
+    plonk::{create_proof, keygen_pk, keygen_vk, verify_proof, Error, ProvingKey, VerifyingKey},
+    poly::{
+        commitment::{Params, ParamsProver},
+        ipa::{
+            commitment::{IPACommitmentScheme, ParamsIPA},
+            multiopen::ProverIPA,
+            strategy::SingleStrategy,
+        },
+        kzg::{
+            commitment::{KZGCommitmentScheme, ParamsKZG},
+            multiopen::{ProverGWC, VerifierGWC},
+            strategy::AccumulatorStrategy,
+        },
+        VerificationStrategy,
+    },
+    transcript::{
+        Blake2bRead, Blake2bWrite, Challenge255, TranscriptReadBuffer, TranscriptWriterBuffer,
+    },
+};
+use halo2curves::{
+    bn256::{Bn256, Fq, Fr, G1Affine},
+    pasta::{vesta, EqAffine, Fp},
+};
+use rand::rngs::OsRng;
+use snark_verifier::{
+    loader::evm::{self, EvmLoader},
+    pcs::kzg::{Gwc19, KzgAs},
+    system::halo2::{compile, transcript::evm::EvmTranscript, Config},
+    verifier::{self, SnarkVerifier},
+    Error as SnarkError,
+};
+use std::fmt::{self, Debug};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::encoding::{field_from_bytes, DecodeError};
+use crate::LeafHashCircuit;
+
+type PlonkVerifier = verifier::plonk::PlonkVerifier<KzgAs<Bn256, Gwc19>>;
+
+/// A commitment scheme and transcript pair the leaf hash circuit can be proven with.
+pub trait Backend: Clone + Debug {
+    type Field: FieldExt;
+    type Curve: CurveAffine<ScalarExt = Self::Field>;
+    type Params: Clone + Debug;
+
+    fn keygen(
+        params: &Self::Params,
+        circuit: &LeafHashCircuit<Self::Field>,
+    ) -> Result<ProvingKey<Self::Curve>, Error>;
+
+    fn prove(
+        params: &Self::Params,
+        pk: &ProvingKey<Self::Curve>,
+        circuit: LeafHashCircuit<Self::Field>,
+        expected: Self::Field,
+    ) -> Result<Vec<u8>, Error>;
+
+    fn verify(
+        params: &Self::Params,
+        vk: &VerifyingKey<Self::Curve>,
+        proof: &[u8],
+        expected: Self::Field,
+    ) -> Result<(), Error>;
+}
+
+/// IPA over the pasta cycle with a Blake2b transcript.
+#[derive(Clone, Debug)]
+pub struct Ipa;
+
+/// KZG over BN254 with the Keccak transcript used by the Solidity verifier, so
+/// proofs can be submitted on-chain.
+///
+/// There is no `setup` for this backend: its parameters must come from a
+/// trusted setup ceremony and be passed to `LeafHashProver::from_params`.
+#[derive(Clone, Debug)]
+pub struct KzgEvm;
+
+impl Backend for Ipa {
+    type Field = Fp;
+    type Curve = EqAffine;
+    type Params = ParamsIPA<vesta::Affine>;
+
+    fn keygen(
+        params: &Self::Params,
+        circuit: &LeafHashCircuit<Fp>,
+    ) -> Result<ProvingKey<EqAffine>, Error> {
+        let vk = keygen_vk(params, circuit)?;
+        keygen_pk(params, vk, circuit)
+    }
+
+    fn prove(
+        params: &Self::Params,
+        pk: &ProvingKey<EqAffine>,
+        circuit: LeafHashCircuit<Fp>,
+        expected: Fp,
+    ) -> Result<Vec<u8>, Error> {
+        let mut transcript = Blake2bWrite::<_, EqAffine, Challenge255<_>>::init(vec![]);
+
+        create_proof::<IPACommitmentScheme<_>, ProverIPA<_>, _, _, _, _>(
+            params,
+            pk,
+            &[circuit],
+            &[&[&[expected]]],
+            OsRng,
+            &mut transcript,
+        )?;
+
+        Ok(transcript.finalize())
+    }
+
+    fn verify(
+        params: &Self::Params,
+        vk: &VerifyingKey<EqAffine>,
+        proof: &[u8],
+        expected: Fp,
+    ) -> Result<(), Error> {
+        let strategy = SingleStrategy::new(params);
+        let mut transcript = Blake2bRead::<_, _, Challenge255<_>>::init(proof);
+
+        verify_proof(params, vk, strategy, &[&[&[expected]]], &mut transcript)
+    }
+}
+
+impl Backend for KzgEvm {
+    type Field = Fr;
+    type Curve = G1Affine;
+    type Params = ParamsKZG<Bn256>;
+
+    fn keygen(
+        params: &Self::Params,
+        circuit: &LeafHashCircuit<Fr>,
+    ) -> Result<ProvingKey<G1Affine>, Error> {
+        let vk = keygen_vk(params, circuit)?;
+        keygen_pk(params, vk, circuit)
+    }
+
+    fn prove(
+        params: &Self::Params,
+        pk: &ProvingKey<G1Affine>,
+        circuit: LeafHashCircuit<Fr>,
+        expected: Fr,
+    ) -> Result<Vec<u8>, Error> {
+        let mut transcript = TranscriptWriterBuffer::<_, G1Affine, _>::init(Vec::new());
+
+        create_proof::<KZGCommitmentScheme<Bn256>, ProverGWC<_>, _, _, EvmTranscript<_, _, _, _>, _>(
+            params,
+            pk,
+            &[circuit],
+            &[&[&[expected]]],
+            OsRng,
+            &mut transcript,
+        )?;
+
+        Ok(transcript.finalize())
+    }
+
+    fn verify(
+        params: &Self::Params,
+        vk: &VerifyingKey<G1Affine>,
+        proof: &[u8],
+        expected: Fr,
+    ) -> Result<(), Error> {
+        let mut transcript = TranscriptReadBuffer::<_, G1Affine, _>::init(proof);
+
+        let strategy = verify_proof::<_, VerifierGWC<_>, _, EvmTranscript<_, _, _, _>, _>(
+            params.verifier_params(),
+            vk,
+            AccumulatorStrategy::new(params.verifier_params()),
+            &[&[&[expected]]],
+            &mut transcript,
+        )?;
+
+        if VerificationStrategy::<_, VerifierGWC<_>>::finalize(strategy) {
+            Ok(())
+        } else {
+            Err(Error::ConstraintSystemFailure)
+        }
+    }
+}
+
+/// Holds the parameters and proving key of the leaf hash circuit so that
+/// keygen only runs once per process.
+#[derive(Clone, Debug)]
+pub struct LeafHashProver<B: Backend = Ipa> {
+    params: B::Params,
+    pk: ProvingKey<B::Curve>,
+}
+
+impl LeafHashProver<Ipa> {
+    /// Generates IPA parameters for `2^k` rows. The IPA setup is transparent,
+    /// so this is safe to run locally.
+    pub fn new(k: u32) -> Result<Self, Error> {
+        Self::from_params(ParamsIPA::new(k))
+    }
+}
+
+impl<B: Backend> LeafHashProver<B> {
+    pub const DEFAULT_K: u32 = 7;
+
+    /// Runs keygen against existing parameters, e.g. a KZG trusted setup.
+    pub fn from_params(params: B::Params) -> Result<Self, Error> {
+        let empty_circuit = LeafHashCircuit::<B::Field>::new([B::Field::zero(); 8]);
+        let pk = B::keygen(&params, &empty_circuit)?;
+        Ok(Self { params, pk })
+    }
+
+    pub fn verifier(&self) -> LeafHashVerifier<B> {
+        LeafHashVerifier {
+            params: self.params.clone(),
+            vk: self.pk.get_vk().clone(),
+        }
+    }
+
+    /// Proves that `message` hashes to `expected` and returns the serialized proof.
+    pub fn prove(&self, message: [B::Field; 8], expected: B::Field) -> Result<Vec<u8>, Error> {
+        B::prove(
+            &self.params,
+            &self.pk,
+            LeafHashCircuit::new(message),
+            expected,
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LeafHashVerifier<B: Backend = Ipa> {
+    params: B::Params,
+    vk: VerifyingKey<B::Curve>,
+}
+
+impl<B: Backend> LeafHashVerifier<B> {
+    pub fn verify(&self, proof: &[u8], expected: B::Field) -> Result<(), Error> {
+        B::verify(&self.params, &self.vk, proof, expected)
+    }
+}
+
+impl LeafHashVerifier<KzgEvm> {
+    /// Returns the deployment bytecode of a Solidity verifier for this circuit.
+    pub fn gen_evm_verifier(&self) -> Result<Vec<u8>, ProverError> {
+        let num_instance = vec![1];
+        let protocol = compile(
+            &self.params,
+            &self.vk,
+            Config::kzg().with_num_instance(num_instance.clone()),
+        );
+        let vk = (self.params.get_g()[0], self.params.g2(), self.params.s_g2()).into();
+
+        let loader = EvmLoader::new::<Fq, Fr>();
+        let protocol = protocol.loaded(&loader);
+        let mut transcript = EvmTranscript::<_, Rc<EvmLoader>, _, _>::new(&loader);
+
+        let instances = transcript.load_instances(num_instance);
+        let proof = PlonkVerifier::read_proof(&vk, &protocol, &instances, &mut transcript)?;
+        PlonkVerifier::verify(&vk, &protocol, &instances, &proof)?;
+
+        Ok(evm::compile_yul(&loader.yul_code()))
+    }
+}
+
+/// A backend chosen at run time, e.g. from a config file or command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    Ipa,
+    KzgEvm,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "ipa" => Ok(BackendKind::Ipa),
+            "kzg-evm" => Ok(BackendKind::KzgEvm),
+            _ => Err(format!("unknown backend {}, expected ipa or kzg-evm", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProverError {
+    Decode(DecodeError),
+    Plonk(Error),
+    Verifier(SnarkError),
+    /// `BackendKind::KzgEvm` was picked without a ceremony SRS.
+    MissingSrs,
+    /// The SRS supports fewer rows than the requested `k`.
+    SrsTooSmall {
+        k: u32,
+        srs_k: u32,
+    },
+}
+
+impl fmt::Display for ProverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProverError::Decode(err) => write!(f, "{}", err),
+            ProverError::Plonk(err) => write!(f, "{}", err),
+            ProverError::Verifier(err) => write!(f, "{:?}", err),
+            ProverError::MissingSrs => write!(f, "the kzg-evm backend needs a ceremony SRS"),
+            ProverError::SrsTooSmall { k, srs_k } => {
+                write!(f, "SRS has k = {}, circuit needs k = {}", srs_k, k)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProverError {}
+
+impl From<DecodeError> for ProverError {
+    fn from(err: DecodeError) -> Self {
+        ProverError::Decode(err)
+    }
+}
+
+impl From<Error> for ProverError {
+    fn from(err: Error) -> Self {
+        ProverError::Plonk(err)
+    }
+}
+
+impl From<SnarkError> for ProverError {
+    fn from(err: SnarkError) -> Self {
+        ProverError::Verifier(err)
+    }
+}
+
+fn decode_message<F: FieldExt>(
+    message: &[[u8; 32]; 8],
+    expected: &[u8; 32],
+) -> Result<([F; 8], F), DecodeError> {
+    let mut words = [F::zero(); 8];
+    for (word, bytes) in words.iter_mut().zip(message.iter()) {
+        *word = field_from_bytes(bytes)?;
+    }
+    Ok((words, field_from_bytes(expected)?))
+}
+
+/// A `LeafHashProver` over either backend, picked by a `BackendKind`.
+///
+/// The two backends prove over different fields, so messages and digests are
+/// passed as 32-byte little-endian field encodings and checked to be
+/// canonical for the chosen field.
+#[derive(Clone, Debug)]
+pub enum AnyLeafHashProver {
+    Ipa(LeafHashProver<Ipa>),
+    KzgEvm(LeafHashProver<KzgEvm>),
+}
+
+impl AnyLeafHashProver {
+    /// `srs` is the KZG parameters from a trusted setup ceremony. It is
+    /// required for `BackendKind::KzgEvm`, downsized to `k` if larger, and
+    /// ignored for IPA, whose setup is transparent.
+    pub fn new(
+        kind: BackendKind,
+        k: u32,
+        srs: Option<ParamsKZG<Bn256>>,
+    ) -> Result<Self, ProverError> {
+        Ok(match kind {
+            BackendKind::Ipa => AnyLeafHashProver::Ipa(LeafHashProver::new(k)?),
+            BackendKind::KzgEvm => {
+                let mut params = srs.ok_or(ProverError::MissingSrs)?;
+                if params.k() < k {
+                    return Err(ProverError::SrsTooSmall {
+                        k,
+                        srs_k: params.k(),
+                    });
+                }
+                params.downsize(k);
+                AnyLeafHashProver::KzgEvm(LeafHashProver::from_params(params)?)
+            }
+        })
+    }
+
+    pub fn kind(&self) -> BackendKind {
+        match self {
+            AnyLeafHashProver::Ipa(_) => BackendKind::Ipa,
+            AnyLeafHashProver::KzgEvm(_) => BackendKind::KzgEvm,
+        }
+    }
+
+    pub fn verifier(&self) -> AnyLeafHashVerifier {
+        match self {
+            AnyLeafHashProver::Ipa(prover) => AnyLeafHashVerifier::Ipa(prover.verifier()),
+            AnyLeafHashProver::KzgEvm(prover) => AnyLeafHashVerifier::KzgEvm(prover.verifier()),
+        }
+    }
+
+    pub fn prove(
+        &self,
+        message: &[[u8; 32]; 8],
+        expected: &[u8; 32],
+    ) -> Result<Vec<u8>, ProverError> {
+        Ok(match self {
+            AnyLeafHashProver::Ipa(prover) => {
+                let (message, expected) = decode_message(message, expected)?;
+                prover.prove(message, expected)?
+            }
+            AnyLeafHashProver::KzgEvm(prover) => {
+                let (message, expected) = decode_message(message, expected)?;
+                prover.prove(message, expected)?
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum AnyLeafHashVerifier {
+    Ipa(LeafHashVerifier<Ipa>),
+    KzgEvm(LeafHashVerifier<KzgEvm>),
+}
+
+impl AnyLeafHashVerifier {
+    pub fn verify(&self, proof: &[u8], expected: &[u8; 32]) -> Result<(), ProverError> {
+        match self {
+            AnyLeafHashVerifier::Ipa(verifier) => {
+                Ok(verifier.verify(proof, field_from_bytes(expected)?)?)
+            }
+            AnyLeafHashVerifier::KzgEvm(verifier) => {
+                Ok(verifier.verify(proof, field_from_bytes(expected)?)?)
+            }
+        }
+    }
+}
+
+/// Encodes the digest and a `KzgEvm` proof as calldata for the generated verifier.
+pub fn encode_calldata(expected: Fr, proof: &[u8]) -> Vec<u8> {
+    evm::encode_calldata(&[vec![expected]], proof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_message;
+
+    #[test]
+    fn leaf_hash_prover_test() {
+        let prover = LeafHashProver::<Ipa>::new(LeafHashProver::<Ipa>::DEFAULT_K).unwrap();
+        let verifier = prover.verifier();
+
+        let message: [Fp; 8] = [(); 8].map(|_| Fp::random(OsRng));
+        let expected = hash_message(message);
+
+        let proof = prover.prove(message, expected).unwrap();
+        assert!(verifier.verify(&proof, expected).is_ok());
+        assert!(verifier.verify(&proof, expected + Fp::one()).is_err());
+    }
+
+    // A local setup whose toxic waste is known; only usable in tests.
+    fn insecure_srs(k: u32) -> ParamsKZG<Bn256> {
+        ParamsKZG::<Bn256>::setup(k, OsRng)
+    }
+
+    #[test]
+    fn kzg_evm_prover_test() {
+        let k = LeafHashProver::<KzgEvm>::DEFAULT_K;
+        let prover = LeafHashProver::<KzgEvm>::from_params(insecure_srs(k)).unwrap();
+        let verifier = prover.verifier();
+
+        let message: [Fr; 8] = [(); 8].map(|_| Fr::random(OsRng));
+        let expected = hash_message(message);
+
+        let proof = prover.prove(message, expected).unwrap();
+        assert!(verifier.verify(&proof, expected).is_ok());
+        assert!(verifier.verify(&proof, expected + Fr::one()).is_err());
+
+        let deployment_code = verifier.gen_evm_verifier().unwrap();
+        assert!(!deployment_code.is_empty());
+
+        let calldata = encode_calldata(expected, &proof);
+        assert_eq!(calldata.len(), 32 + proof.len());
+    }
+
+    fn to_bytes<F: FieldExt>(value: F) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(value.to_repr().as_ref());
+        bytes
+    }
+
+    fn prove_at_runtime<F: FieldExt>(kind: BackendKind) {
+        let k = LeafHashProver::<Ipa>::DEFAULT_K;
+        let prover = AnyLeafHashProver::new(kind, k, Some(insecure_srs(k + 1))).unwrap();
+        assert_eq!(prover.kind(), kind);
+        let verifier = prover.verifier();
+
+        let message: [F; 8] = [(); 8].map(|_| F::random(OsRng));
+        let expected = to_bytes(hash_message(message));
+        let message = message.map(to_bytes);
+
+        let proof = prover.prove(&message, &expected).unwrap();
+        assert!(verifier.verify(&proof, &expected).is_ok());
+        assert!(verifier.verify(&proof, &[0u8; 32]).is_err());
+        assert!(matches!(
+            prover.prove(&message, &[0xff; 32]),
+            Err(ProverError::Decode(DecodeError::NonCanonicalField))
+        ));
+    }
+
+    #[test]
+    fn runtime_backend_test() {
+        prove_at_runtime::<Fp>("ipa".parse().unwrap());
+        prove_at_runtime::<Fr>("kzg-evm".parse().unwrap());
+        assert!("groth16".parse::<BackendKind>().is_err());
+
+        assert!(matches!(
+            AnyLeafHashProver::new(BackendKind::KzgEvm, 7, None),
+            Err(ProverError::MissingSrs)
+        ));
+        assert!(matches!(
+            AnyLeafHashProver::new(BackendKind::KzgEvm, 7, Some(insecure_srs(6))),
+            Err(ProverError::SrsTooSmall { k: 7, srs_k: 6 })
+        ));
+    }
+}
@@ -0,0 +1,95 @@
+use halo2_gadgets::poseidon::primitives::{Domain, Spec};
+use halo2_proofs::arithmetic::FieldExt;
+use std::iter;
+
+/// Domain for messages whose length is not fixed by the circuit shape.
+///
+/// The message is padded with a single one followed by zeroes up to a
+/// multiple of RATE, and the capacity is tagged so that it can never meet a
+/// `ConstantLength` digest (whose capacity element is `L << 64`).
+#[derive(Clone, Copy, Debug)]
+pub struct VariableLength;
+
+impl<F: FieldExt, const RATE: usize> Domain<F, RATE> for VariableLength {
+    type Padding = iter::Chain<iter::Once<F>, iter::Take<iter::Repeat<F>>>;
+
+    fn name() -> String {
+        "VariableLength".to_string()
+    }
+
+    fn initial_capacity_element() -> F {
+        F::one()
+    }
+
+    fn padding(input_len: usize) -> Self::Padding {
+        let zeroes = RATE - 1 - input_len % RATE;
+        iter::once(F::one()).chain(iter::repeat(F::zero()).take(zeroes))
+    }
+}
+
+/// Native Poseidon permutation, matching the one constrained by `Pow5Chip`.
+pub fn permute<F, S, const WIDTH: usize, const RATE: usize>(
+    state: &mut [F; WIDTH],
+    mds: &[[F; WIDTH]; WIDTH],
+    round_constants: &[[F; WIDTH]],
+) where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+{
+    let r_f = S::full_rounds() / 2;
+    let r_p = S::partial_rounds();
+
+    let apply_mds = |state: &mut [F; WIDTH]| {
+        let mut new_state = [F::zero(); WIDTH];
+        for (new_word, row) in new_state.iter_mut().zip(mds.iter()) {
+            *new_word = row
+                .iter()
+                .zip(state.iter())
+                .fold(F::zero(), |acc, (m, word)| acc + m * word);
+        }
+        *state = new_state;
+    };
+
+    for (round, rcs) in round_constants.iter().enumerate() {
+        for (word, rc) in state.iter_mut().zip(rcs.iter()) {
+            *word += rc;
+        }
+        if round < r_f || round >= r_f + r_p {
+            for word in state.iter_mut() {
+                *word = S::sbox(*word);
+            }
+        } else {
+            state[0] = S::sbox(state[0]);
+        }
+        apply_mds(state);
+    }
+}
+
+/// Absorbs `message` and its domain padding RATE words at a time, permuting
+/// after each block, and squeezes a single word.
+pub(crate) fn hash<F, S, D, const WIDTH: usize, const RATE: usize>(message: &[F]) -> F
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+    D: Domain<F, RATE>,
+{
+    let (round_constants, mds, _) = S::constants();
+    let mut state = [F::zero(); WIDTH];
+    state[RATE] = D::initial_capacity_element();
+
+    let padded: Vec<F> = message
+        .iter()
+        .copied()
+        .chain(D::padding(message.len()))
+        .collect();
+    assert_eq!(padded.len() % RATE, 0);
+
+    for block in padded.chunks(RATE) {
+        for (word, value) in state.iter_mut().zip(block.iter()) {
+            *word += value;
+        }
+        permute::<F, S, WIDTH, RATE>(&mut state, &mds, &round_constants);
+    }
+
+    state[0]
+}
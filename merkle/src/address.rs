@@ -0,0 +1,606 @@
+use halo2_gadgets::poseidon::primitives::Spec;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner, Value};
+use halo2_proofs::plonk::{Circuit, ConstraintSystem, Error};
+use num_bigint::BigUint;
+use smt::poseidon::FieldHasher;
+use std::array;
+use std::marker::PhantomData;
+
+use crate::min_k;
+use crate::path::MerklePathChip;
+use crate::store::{AddressNodeChange, AddressStore, MemoryStore};
+use crate::tree::empty_hashes;
+use crate::update::{MerkleUpdateConfig, NEW_ROOT_ROW, OLD_ROOT_ROW};
+
+pub const ADDRESS_ROW: usize = 2;
+
+/// Sibling hashes from the leaf level up, and the address whose bit `i` is
+/// set when the node at level `i` is a right child.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddressPath<F: FieldExt, const N: usize> {
+    pub siblings: [F; N],
+    pub address: F,
+}
+
+impl<F: FieldExt, const N: usize> AddressPath<F, N> {
+    /// The bits of the canonical form of the address, leaf level first.
+    pub fn bits(&self) -> [bool; N] {
+        let address = to_biguint(&self.address);
+        array::from_fn(|level| address.bit(level as u64))
+    }
+
+    /// Hashes `leaf` up to the root along this path.
+    pub fn root<H: FieldHasher<F, 2>>(&self, leaf: F, hasher: &H) -> F {
+        self.siblings
+            .iter()
+            .zip(self.bits())
+            .fold(leaf, |node, (sibling, is_right)| {
+                let pair = if is_right {
+                    [*sibling, node]
+                } else {
+                    [node, *sibling]
+                };
+                hasher.hash(pair).unwrap()
+            })
+    }
+}
+
+/// Replaces `old_leaf` by `new_leaf` at the address of `path`.
+///
+/// `new_value` is the value `new_leaf` commits to, or `None` when the update
+/// empties the slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddressUpdate<F: FieldExt, const N: usize> {
+    pub path: AddressPath<F, N>,
+    pub old_leaf: F,
+    pub new_leaf: F,
+    pub new_value: Option<F>,
+}
+
+impl<F: FieldExt, const N: usize> AddressUpdate<F, N> {
+    pub fn old_root<H: FieldHasher<F, 2>>(&self, hasher: &H) -> F {
+        self.path.root(self.old_leaf, hasher)
+    }
+
+    pub fn new_root<H: FieldHasher<F, 2>>(&self, hasher: &H) -> F {
+        self.path.root(self.new_leaf, hasher)
+    }
+}
+
+fn to_biguint<F: FieldExt>(value: &F) -> BigUint {
+    BigUint::from_bytes_le(value.to_repr().as_ref())
+}
+
+/// A sparse Merkle tree keyed by account address rather than by an
+/// operator-assigned id.
+///
+/// The tree has one level per bit of the field, `N = F::NUM_BITS`, and the
+/// leaf of an address sits at the position given by its canonical bits, so
+/// every address has a slot of its own and no two can collide. The leaf there
+/// is `hash(address, value)`. As in `MerkleTree`, empty subtrees are cached
+/// rather than stored, so a write costs `N` hashes and one store write.
+///
+/// Paths are not compressed: a leaf is never shortcut to the first level
+/// where its address parts from its neighbours. That would save hashes, but
+/// leaves would then move as neighbours arrive, and `AddressUpdateCircuit`
+/// would have to prove paths of varying depth.
+///
+/// The node at a level is stored under the address bits from that level up,
+/// in the `AddressStore` key space, and `AddressUpdateCircuit` checks that the
+/// path it hashes along is the one of its public address.
+#[derive(Clone, Debug)]
+pub struct AddressTree<
+    F: FieldExt,
+    H: FieldHasher<F, 2>,
+    const N: usize,
+    S: AddressStore<F> = MemoryStore<F>,
+> {
+    store: S,
+    empty: Vec<F>,
+    hasher: H,
+}
+
+impl<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> AddressTree<F, H, N> {
+    pub fn new(empty_leaf: F, hasher: H) -> Self {
+        Self::with_store(MemoryStore::default(), empty_leaf, hasher)
+    }
+}
+
+impl<F, H, const N: usize, S> AddressTree<F, H, N, S>
+where
+    F: FieldExt,
+    H: FieldHasher<F, 2>,
+    S: AddressStore<F>,
+{
+    /// `N`, checked at compile time to be the bit size of the field.
+    const LEVELS: usize = {
+        assert!(
+            N == F::NUM_BITS as usize,
+            "an address tree has one level per bit of the field"
+        );
+        N
+    };
+
+    /// Opens a tree over the nodes already in `store`.
+    pub fn with_store(store: S, empty_leaf: F, hasher: H) -> Self {
+        Self {
+            store,
+            empty: empty_hashes(empty_leaf, &hasher, Self::LEVELS),
+            hasher,
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    pub fn hasher(&self) -> &H {
+        &self.hasher
+    }
+
+    pub fn empty_leaf(&self) -> F {
+        self.empty[0]
+    }
+
+    fn node(&self, level: usize, key: &BigUint) -> Result<F, S::Error> {
+        Ok(self
+            .store
+            .get_address_node(level, &key.to_bytes_le())?
+            .unwrap_or(self.empty[level]))
+    }
+
+    pub fn root(&self) -> Result<F, S::Error> {
+        self.node(N, &BigUint::default())
+    }
+
+    /// The leaf committing to `value` under `address`.
+    pub fn leaf(&self, address: F, value: F) -> F {
+        self.hasher.hash([address, value]).unwrap()
+    }
+
+    pub fn contains(&self, address: &F) -> Result<bool, S::Error> {
+        Ok(self.node(0, &to_biguint(address))? != self.empty_leaf())
+    }
+
+    /// The siblings of the leaf of `address`, leaf level first.
+    pub fn path(&self, address: &F) -> Result<AddressPath<F, N>, S::Error> {
+        let address_bits = to_biguint(address);
+        let mut siblings = [F::zero(); N];
+        for (level, sibling) in siblings.iter_mut().enumerate() {
+            let mut key = &address_bits >> level;
+            key.set_bit(0, !key.bit(0));
+            *sibling = self.node(level, &key)?;
+        }
+        Ok(AddressPath {
+            siblings,
+            address: *address,
+        })
+    }
+
+    /// Shows `address` has no account, or returns `None` if it has one.
+    pub fn non_membership_proof(&self, address: &F) -> Result<Option<AddressPath<F, N>>, S::Error> {
+        if self.contains(address)? {
+            return Ok(None);
+        }
+        self.path(address).map(Some)
+    }
+
+    /// Sets the value of `address`, inserting it if needed, and returns the
+    /// update for `AddressUpdateCircuit`.
+    pub fn insert(&mut self, address: F, value: F) -> Result<AddressUpdate<F, N>, S::Error> {
+        self.update(address, Some(value))
+    }
+
+    /// Empties the slot of `address`, or returns `None` if it has no account.
+    pub fn remove(&mut self, address: &F) -> Result<Option<AddressUpdate<F, N>>, S::Error> {
+        if !self.contains(address)? {
+            return Ok(None);
+        }
+        self.update(*address, None).map(Some)
+    }
+
+    /// Writes the leaf of `value` at `address`, or the empty leaf for `None`,
+    /// with the `N + 1` nodes on its path in a single store write, and returns
+    /// the update.
+    fn update(&mut self, address: F, value: Option<F>) -> Result<AddressUpdate<F, N>, S::Error> {
+        let address_bits = to_biguint(&address);
+        let path = self.path(&address)?;
+        let leaf = value.map_or(self.empty_leaf(), |value| self.leaf(address, value));
+        let update = AddressUpdate {
+            path,
+            old_leaf: self.node(0, &address_bits)?,
+            new_leaf: leaf,
+            new_value: value,
+        };
+
+        let mut changes: Vec<AddressNodeChange<F>> = Vec::with_capacity(N + 1);
+        let mut node = leaf;
+        for (level, (sibling, is_right)) in path.siblings.iter().zip(path.bits()).enumerate() {
+            changes.push(self.change(level, &address_bits, node));
+            let pair = if is_right {
+                [*sibling, node]
+            } else {
+                [node, *sibling]
+            };
+            node = self.hasher.hash(pair).unwrap();
+        }
+        changes.push(self.change(N, &address_bits, node));
+
+        self.store.write_address_nodes(&changes)?;
+        Ok(update)
+    }
+
+    fn change(&self, level: usize, address_bits: &BigUint, node: F) -> AddressNodeChange<F> {
+        (
+            level,
+            (address_bits >> level).to_bytes_le(),
+            (node != self.empty[level]).then_some(node),
+        )
+    }
+}
+
+/// Proves that replacing `old_leaf` by `new_leaf` at the address at
+/// `ADDRESS_ROW` moves an `AddressTree` from the root at `OLD_ROOT_ROW` to
+/// the root at `NEW_ROOT_ROW`.
+///
+/// The path bits are constrained to be the canonical bits of the address, so
+/// the update cannot be applied to any other slot, and the new leaf is
+/// computed in the circuit as `hash(address, value)`, or loaded as the
+/// constant empty leaf when the slot is emptied. The old leaf is only bound
+/// by the old root, whose leaves earlier updates constrained the same way.
+#[derive(Clone, Debug)]
+pub struct AddressUpdateCircuit<
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+    const WIDTH: usize,
+    const RATE: usize,
+    const N: usize,
+> {
+    path: Value<AddressPath<F, N>>,
+    old_leaf: Value<F>,
+    new_value: Value<Option<F>>,
+    empty_leaf: F,
+    address: F,
+    old_root: F,
+    new_root: F,
+    _spec: PhantomData<S>,
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize>
+    AddressUpdateCircuit<F, S, WIDTH, RATE, N>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+{
+    /// Proves `update` in a tree whose empty leaf is `empty_leaf`.
+    pub fn new<H: FieldHasher<F, 2>>(
+        update: AddressUpdate<F, N>,
+        empty_leaf: F,
+        hasher: &H,
+    ) -> Self {
+        Self {
+            path: Value::known(update.path),
+            old_leaf: Value::known(update.old_leaf),
+            new_value: Value::known(update.new_value),
+            empty_leaf,
+            address: update.path.address,
+            old_root: update.old_root(hasher),
+            new_root: update.new_root(hasher),
+            _spec: PhantomData,
+        }
+    }
+
+    /// The hashes of both roots and of the new leaf; the bit checks sit
+    /// beside them.
+    pub fn rows() -> usize {
+        2 * MerklePathChip::<F, S, WIDTH, RATE>::rows_per_root::<N>()
+            + MerklePathChip::<F, S, WIDTH, RATE>::rows_per_hash()
+    }
+
+    pub fn min_k() -> u32
+    where
+        S: Clone,
+    {
+        min_k::<F, Self>(Self::rows())
+    }
+
+    pub fn num_instance() -> Vec<usize> {
+        vec![3]
+    }
+
+    pub fn instances(&self) -> Vec<Vec<F>> {
+        let mut instances = vec![F::zero(); 3];
+        instances[OLD_ROOT_ROW] = self.old_root;
+        instances[NEW_ROOT_ROW] = self.new_root;
+        instances[ADDRESS_ROW] = self.address;
+        vec![instances]
+    }
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize> Circuit<F>
+    for AddressUpdateCircuit<F, S, WIDTH, RATE, N>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Clone,
+{
+    type Config = MerkleUpdateConfig<F, WIDTH, RATE>;
+    type FloorPlanner = SimpleFloorPlanner;
+
+    fn without_witnesses(&self) -> Self {
+        Self {
+            path: Value::unknown(),
+            old_leaf: Value::unknown(),
+            new_value: Value::unknown(),
+            empty_leaf: self.empty_leaf,
+            address: F::zero(),
+            old_root: F::zero(),
+            new_root: F::zero(),
+            _spec: PhantomData,
+        }
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        MerkleUpdateConfig::configure::<S>(meta)
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>,
+    ) -> Result<(), Error> {
+        let chip = config.chip::<S>();
+        let (old_leaf, value, occupied, empty_leaf, address) = layouter.assign_region(
+            || "load leaves and address",
+            |mut region| {
+                let old_leaf =
+                    region.assign_advice(|| "old leaf", config.leaves, 0, || self.old_leaf)?;
+                let value = region.assign_advice(
+                    || "new value",
+                    config.leaves,
+                    1,
+                    || self.new_value.map(|value| value.unwrap_or_else(F::zero)),
+                )?;
+                let occupied = region.assign_advice(
+                    || "occupied",
+                    config.leaves,
+                    2,
+                    || self.new_value.map(|value| F::from(value.is_some() as u64)),
+                )?;
+                let empty_leaf = region.assign_advice_from_constant(
+                    || "empty leaf",
+                    config.leaves,
+                    3,
+                    self.empty_leaf,
+                )?;
+                let address = region.assign_advice(
+                    || "address",
+                    config.leaves,
+                    4,
+                    || self.path.map(|path| path.address),
+                )?;
+                Ok((old_leaf, value, occupied, empty_leaf, address))
+            },
+        )?;
+        let leaf = chip.hash(layouter.namespace(|| "new leaf"), address.clone(), value)?;
+        let new_leaf = chip.select(
+            layouter.namespace(|| "empty or new leaf"),
+            &occupied,
+            &empty_leaf,
+            &leaf,
+        )?;
+        let (bits, siblings) = chip.load_bits(
+            layouter.namespace(|| "path"),
+            self.path.map(|path| path.bits()),
+            self.path.map(|path| path.siblings),
+        )?;
+        chip.constrain_canonical(layouter.namespace(|| "address bits"), &bits, &address)?;
+
+        let old_root = chip.compute_root(
+            layouter.namespace(|| "old root"),
+            &old_leaf,
+            &bits,
+            &siblings,
+        )?;
+        let new_root = chip.compute_root(
+            layouter.namespace(|| "new root"),
+            &new_leaf,
+            &bits,
+            &siblings,
+        )?;
+
+        layouter.constrain_instance(old_root.cell(), config.roots, OLD_ROOT_ROW)?;
+        layouter.constrain_instance(new_root.cell(), config.roots, NEW_ROOT_ROW)?;
+        layouter.constrain_instance(address.cell(), config.roots, ADDRESS_ROW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use halo2_proofs::arithmetic::Field;
+    use halo2_proofs::dev::MockProver;
+    use halo2_proofs::halo2curves::bn256::Fr;
+    use rand::rngs::OsRng;
+    use smt::poseidon::{Poseidon, SmtP128Pow5T3};
+
+    const HEIGHT: usize = 254;
+    type Tree = AddressTree<Fr, Poseidon<Fr, 2>, HEIGHT>;
+
+    /// Checks `bits` against `value` with `constrain_canonical` alone.
+    #[derive(Clone)]
+    struct CanonicalCircuit {
+        bits: [bool; HEIGHT],
+        value: Fr,
+    }
+
+    impl Circuit<Fr> for CanonicalCircuit {
+        type Config = MerkleUpdateConfig<Fr, 3, 2>;
+        type FloorPlanner = SimpleFloorPlanner;
+
+        fn without_witnesses(&self) -> Self {
+            self.clone()
+        }
+
+        fn configure(meta: &mut ConstraintSystem<Fr>) -> Self::Config {
+            MerkleUpdateConfig::configure::<SmtP128Pow5T3<Fr, 0>>(meta)
+        }
+
+        fn synthesize(
+            &self,
+            config: Self::Config,
+            mut layouter: impl Layouter<Fr>,
+        ) -> Result<(), Error> {
+            let chip = config.chip::<SmtP128Pow5T3<Fr, 0>>();
+            let value = layouter.assign_region(
+                || "load value",
+                |mut region| {
+                    region.assign_advice(|| "value", config.leaves, 0, || Value::known(self.value))
+                },
+            )?;
+            let (bits, _) = chip.load_bits(
+                layouter.namespace(|| "bits"),
+                Value::known(self.bits),
+                Value::known([Fr::zero(); HEIGHT]),
+            )?;
+            chip.constrain_canonical(layouter.namespace(|| "canonical"), &bits, &value)
+        }
+    }
+
+    #[test]
+    fn address_tree_test() {
+        let hasher = Poseidon::<Fr, 2>::new();
+        let mut tree = Tree::new(Fr::zero(), hasher.clone());
+        let empty_root = tree.root().unwrap();
+
+        // Addresses sharing their low bits, or at the top of the field, still
+        // get slots of their own.
+        let mut addresses: Vec<Fr> = (0..3).map(|_| Fr::random(OsRng)).collect();
+        addresses.push(addresses[0] + Fr::from(1u64 << 40));
+        addresses.push(-Fr::one());
+        let values: Vec<Fr> = addresses.iter().map(|_| Fr::random(OsRng)).collect();
+
+        for (address, value) in addresses.iter().zip(values.iter()) {
+            let update = tree.insert(*address, *value).unwrap();
+            assert_eq!(update.old_leaf, Fr::zero());
+            assert_eq!(update.new_leaf, hasher.hash([*address, *value]).unwrap());
+            assert_eq!(update.new_root(&hasher), tree.root().unwrap());
+        }
+        for address in addresses.iter() {
+            assert!(tree.contains(address).unwrap());
+            assert!(tree.non_membership_proof(address).unwrap().is_none());
+        }
+
+        // The position only depends on the address, not on insertion order.
+        let mut reversed = Tree::new(Fr::zero(), hasher.clone());
+        for (address, value) in addresses.iter().zip(values.iter()).rev() {
+            reversed.insert(*address, *value).unwrap();
+        }
+        assert_eq!(reversed.root().unwrap(), tree.root().unwrap());
+
+        // Updating an existing account keeps its slot.
+        let update = tree.insert(addresses[0], Fr::one()).unwrap();
+        assert_eq!(update.old_root(&hasher), reversed.root().unwrap());
+        assert_eq!(update.path, reversed.path(&addresses[0]).unwrap());
+
+        let absent = Fr::random(OsRng);
+        let path = tree.non_membership_proof(&absent).unwrap().unwrap();
+        assert_eq!(path.root(Fr::zero(), &hasher), tree.root().unwrap());
+
+        let root = tree.root().unwrap();
+        tree.remove(&addresses[3]).unwrap().unwrap();
+        assert!(!tree.contains(&addresses[3]).unwrap());
+        assert!(tree.contains(&addresses[0]).unwrap());
+        assert_ne!(tree.root().unwrap(), root);
+        assert!(tree.remove(&addresses[3]).unwrap().is_none());
+
+        // Addresses differing only in their leaf-level bit are siblings, each
+        // in the path of the other.
+        let even = Fr::from(6);
+        let odd = Fr::from(7);
+        tree.insert(even, Fr::one()).unwrap();
+        let update = tree.insert(odd, Fr::one()).unwrap();
+        assert_eq!(
+            update.path.siblings[0],
+            hasher.hash([even, Fr::one()]).unwrap()
+        );
+        assert_eq!(
+            update.path.siblings[1..],
+            tree.path(&even).unwrap().siblings[1..]
+        );
+        assert_eq!(tree.path(&even).unwrap().siblings[0], update.new_leaf);
+        addresses.extend([even, odd]);
+
+        // Removing every account leaves nothing stored.
+        for address in addresses.iter() {
+            tree.remove(address).unwrap();
+        }
+        assert_eq!(tree.root().unwrap(), empty_root);
+        assert!(tree.store().is_empty());
+    }
+
+    #[test]
+    fn address_update_circuit_test() {
+        type TestCircuit = AddressUpdateCircuit<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2, HEIGHT>;
+
+        let hasher = Poseidon::<Fr, 2>::new();
+        let mut tree = Tree::new(Fr::zero(), hasher.clone());
+        tree.insert(Fr::random(OsRng), Fr::random(OsRng)).unwrap();
+
+        // `p - 1` keeps every bit of the canonical check tight.
+        let address = -Fr::one();
+        let update = tree.insert(address, Fr::random(OsRng)).unwrap();
+        let circuit = TestCircuit::new(update, Fr::zero(), &hasher);
+        let instances = circuit.instances();
+        assert_eq!(instances[0][NEW_ROOT_ROW], tree.root().unwrap());
+
+        let k = TestCircuit::min_k();
+        let prover = MockProver::run(k, &circuit, instances.clone()).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        // The same path cannot be claimed for another address.
+        let mut wrong_address = instances[0].clone();
+        wrong_address[ADDRESS_ROW] = address - Fr::from(2);
+        let prover = MockProver::run(k, &circuit, vec![wrong_address]).unwrap();
+        assert!(prover.verify().is_err());
+
+        // Nor can a leaf other than `hash(address, value)` be written.
+        let forged = AddressUpdate {
+            new_leaf: Fr::random(OsRng),
+            ..update
+        };
+        let circuit = TestCircuit::new(forged, Fr::zero(), &hasher);
+        let prover = MockProver::run(k, &circuit, circuit.instances()).unwrap();
+        assert!(prover.verify().is_err());
+
+        // Removing the account writes the empty leaf.
+        let update = tree.remove(&address).unwrap().unwrap();
+        let circuit = TestCircuit::new(update, Fr::zero(), &hasher);
+        let prover = MockProver::run(k, &circuit, circuit.instances()).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+    }
+
+    #[test]
+    fn canonical_bits_test() {
+        let modulus = to_biguint(&-Fr::one()) + 1u32;
+        let bits_of =
+            |value: BigUint| -> [bool; HEIGHT] { array::from_fn(|i| value.bit(i as u64)) };
+        let verify = |bits: [bool; HEIGHT], value: Fr| {
+            let circuit = CanonicalCircuit { bits, value };
+            MockProver::run(10, &circuit, vec![vec![]])
+                .unwrap()
+                .verify()
+        };
+
+        for value in [Fr::from(5), Fr::random(OsRng), -Fr::one()] {
+            assert_eq!(verify(bits_of(to_biguint(&value)), value), Ok(()));
+        }
+        // `5 + p` still fits the bits and recomposes to 5.
+        let value = Fr::from(5);
+        assert!(verify(bits_of(to_biguint(&value) + &modulus), value).is_err());
+    }
+}
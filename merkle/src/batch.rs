@@ -0,0 +1,252 @@
+use halo2_gadgets::poseidon::primitives::Spec;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner, Value};
+use halo2_proofs::plonk::{Circuit, ConstraintSystem, Error};
+use smt::poseidon::FieldHasher;
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::path::{AuthPath, MerklePathChip};
+use crate::update::{assign_update, MerkleUpdateConfig, NEW_ROOT_ROW, OLD_ROOT_ROW};
+use crate::{min_k, usable_rows};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchError {
+    /// Update `index` does not start from the root left by the one before it.
+    BrokenChain { index: usize },
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchError::BrokenChain { index } => {
+                write!(f, "update {} does not start from the previous root", index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+/// Replaces `old_leaf` by `new_leaf` at the index of `path`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LeafUpdate<F: FieldExt, const N: usize> {
+    pub path: AuthPath<F, N>,
+    pub old_leaf: F,
+    pub new_leaf: F,
+}
+
+impl<F: FieldExt, const N: usize> LeafUpdate<F, N> {
+    pub fn old_root<H: FieldHasher<F, 2>>(&self, hasher: &H) -> F {
+        self.path.root(self.old_leaf, hasher)
+    }
+
+    pub fn new_root<H: FieldHasher<F, 2>>(&self, hasher: &H) -> F {
+        self.path.root(self.new_leaf, hasher)
+    }
+}
+
+/// Applies `K` leaf updates in order, each against the root left by the one
+/// before it.
+///
+/// The new root of each update is constrained equal to the old root of the
+/// next, so only the root before the batch (`OLD_ROOT_ROW`) and the root after
+/// it (`NEW_ROOT_ROW`) are public.
+#[derive(Clone, Debug)]
+pub struct MerkleBatchUpdateCircuit<
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+    const WIDTH: usize,
+    const RATE: usize,
+    const N: usize,
+    const K: usize,
+> {
+    updates: [Value<LeafUpdate<F, N>>; K],
+    old_root: F,
+    new_root: F,
+    _spec: PhantomData<S>,
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize, const K: usize>
+    MerkleBatchUpdateCircuit<F, S, WIDTH, RATE, N, K>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Clone,
+{
+    /// `K`, checked at compile time to be non-zero.
+    const UPDATES: usize = {
+        assert!(K > 0, "a batch applies at least one update");
+        K
+    };
+
+    pub fn new<H: FieldHasher<F, 2>>(
+        updates: [LeafUpdate<F, N>; K],
+        hasher: &H,
+    ) -> Result<Self, BatchError> {
+        // The root before each update, then the root after the last one.
+        let mut roots = Vec::with_capacity(Self::UPDATES + 1);
+        roots.push(updates[0].old_root(hasher));
+        for (index, update) in updates.iter().enumerate() {
+            if update.old_root(hasher) != roots[index] {
+                return Err(BatchError::BrokenChain { index });
+            }
+            roots.push(update.new_root(hasher));
+        }
+
+        Ok(Self {
+            updates: updates.map(Value::known),
+            old_root: roots[0],
+            new_root: roots[Self::UPDATES],
+            _spec: PhantomData,
+        })
+    }
+
+    /// Rows taken by one update: the old and new root over the same path.
+    pub fn rows_per_update() -> usize {
+        2 * MerklePathChip::<F, S, WIDTH, RATE>::rows_per_root::<N>()
+    }
+
+    /// The largest batch that fits at `k`.
+    pub fn max_updates(k: u32) -> usize {
+        usable_rows::<F, Self>(k) / Self::rows_per_update()
+    }
+
+    /// The smallest `k` that fits a batch of `K` updates.
+    pub fn min_k() -> u32 {
+        min_k::<F, Self>(K * Self::rows_per_update())
+    }
+
+    pub fn num_instance() -> Vec<usize> {
+        vec![2]
+    }
+
+    pub fn instances(&self) -> Vec<Vec<F>> {
+        let mut instances = vec![F::zero(); 2];
+        instances[OLD_ROOT_ROW] = self.old_root;
+        instances[NEW_ROOT_ROW] = self.new_root;
+        vec![instances]
+    }
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize, const K: usize> Circuit<F>
+    for MerkleBatchUpdateCircuit<F, S, WIDTH, RATE, N, K>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Clone,
+{
+    type Config = MerkleUpdateConfig<F, WIDTH, RATE>;
+    type FloorPlanner = SimpleFloorPlanner;
+
+    fn without_witnesses(&self) -> Self {
+        Self {
+            updates: [Value::unknown(); K],
+            old_root: F::zero(),
+            new_root: F::zero(),
+            _spec: PhantomData,
+        }
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        MerkleUpdateConfig::configure::<S>(meta)
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>,
+    ) -> Result<(), Error> {
+        let chip = config.chip::<S>();
+
+        let mut roots = Vec::with_capacity(Self::UPDATES);
+        for (i, update) in self.updates.iter().enumerate() {
+            roots.push(assign_update(
+                &chip,
+                layouter.namespace(|| format!("update {}", i)),
+                config.leaves,
+                update.map(|update| update.path),
+                update.map(|update| update.old_leaf),
+                update.map(|update| update.new_leaf),
+            )?);
+        }
+
+        for (i, pair) in roots.windows(2).enumerate() {
+            let (_, root) = &pair[0];
+            let (old_root, _) = &pair[1];
+            layouter.assign_region(
+                || format!("chain update {}", i + 1),
+                |mut region| region.constrain_equal(root.cell(), old_root.cell()),
+            )?;
+        }
+
+        let (old_root, _) = &roots[0];
+        let (_, new_root) = &roots[Self::UPDATES - 1];
+        layouter.constrain_instance(old_root.cell(), config.roots, OLD_ROOT_ROW)?;
+        layouter.constrain_instance(new_root.cell(), config.roots, NEW_ROOT_ROW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use halo2_proofs::arithmetic::Field;
+    use halo2_proofs::dev::MockProver;
+    use halo2_proofs::halo2curves::bn256::Fr;
+    use rand::rngs::OsRng;
+    use smt::poseidon::{Poseidon, SmtP128Pow5T3};
+    use smt::smt::SparseMerkleTree;
+
+    const HEIGHT: usize = 3;
+    type TestCircuit<const K: usize> =
+        MerkleBatchUpdateCircuit<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2, HEIGHT, K>;
+
+    #[test]
+    fn merkle_batch_update_test() {
+        let empty_leaf = [0u8; 64];
+        let hasher = Poseidon::<Fr, 2>::new();
+        let mut leaves: Vec<Fr> = (0..6).map(|_| Fr::random(OsRng)).collect();
+        let tree = |leaves: &[Fr]| {
+            SparseMerkleTree::<Fr, Poseidon<Fr, 2>, HEIGHT>::new_sequential(
+                leaves,
+                &hasher,
+                &empty_leaf,
+            )
+            .unwrap()
+        };
+        let initial = tree(&leaves);
+
+        // The same index may be updated twice within a batch.
+        let updates = [1u64, 4, 1].map(|index| {
+            let path = AuthPath::from_smt(&tree(&leaves).generate_membership_proof(index), index);
+            let old_leaf = leaves[index as usize];
+            let new_leaf = Fr::random(OsRng);
+            leaves[index as usize] = new_leaf;
+            LeafUpdate {
+                path,
+                old_leaf,
+                new_leaf,
+            }
+        });
+
+        let circuit = TestCircuit::<3>::new(updates, &hasher).unwrap();
+        let instances = circuit.instances();
+        assert_eq!(instances[0][OLD_ROOT_ROW], initial.root());
+        assert_eq!(instances[0][NEW_ROOT_ROW], tree(&leaves).root());
+
+        let k = TestCircuit::<3>::min_k();
+        assert!(TestCircuit::<3>::max_updates(k - 1) < 3);
+        let prover = MockProver::run(k, &circuit, instances.clone()).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        // An intermediate root cannot be claimed as the final one.
+        let mut wrong = instances[0].clone();
+        wrong[NEW_ROOT_ROW] = updates[1].new_root(&hasher);
+        let prover = MockProver::run(k, &circuit, vec![wrong]).unwrap();
+        assert!(prover.verify().is_err());
+
+        // Updates that do not chain are rejected before proving.
+        assert_eq!(
+            TestCircuit::<2>::new([updates[0], updates[2]], &hasher).err(),
+            Some(BatchError::BrokenChain { index: 1 })
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use halo2_gadgets::poseidon::primitives::Spec;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner, Value};
+use halo2_proofs::plonk::{
+    Advice, Circuit, Column, ConstraintSystem, Error, Expression, Instance, Selector,
+};
+use halo2_proofs::poly::Rotation;
+use smt::poseidon::FieldHasher;
+use std::marker::PhantomData;
+
+use crate::incremental::{IncrementalError, IncrementalTree};
+use crate::path::{AuthPath, MerklePathChip, MerklePathConfig};
+use crate::{min_k, NEW_ROOT_ROW, OLD_ROOT_ROW};
+
+/// Instance row of the index of the first consumed deposit.
+pub const START_ROW: usize = 2;
+/// Instance row of the first consumed deposit leaf; the `K` leaves follow in
+/// deposit order.
+pub const FIRST_DEPOSIT_ROW: usize = 3;
+
+#[derive(Clone, Debug)]
+pub struct DepositRangeConfig<F: FieldExt, const WIDTH: usize, const RATE: usize> {
+    path_config: MerklePathConfig<F, WIDTH, RATE>,
+    index: Column<Advice>,
+    s_next: Selector,
+    instance: Column<Instance>,
+}
+
+/// Proves that a batch consumed deposits `start..start + K` of the deposit
+/// tree, in order.
+///
+/// The public deposit leaves are appended one by one to the empty slots at
+/// consecutive indices, chaining roots from the tree of `start` deposits
+/// (`OLD_ROOT_ROW`) to the tree of `start + K` deposits (`NEW_ROOT_ROW`).
+/// Each index is bound to its path bits, so the operator can neither skip a
+/// deposit nor reorder them.
+#[derive(Clone, Debug)]
+pub struct DepositRangeCircuit<
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+    const WIDTH: usize,
+    const RATE: usize,
+    const N: usize,
+    const K: usize,
+> {
+    paths: [Value<AuthPath<F, N>>; K],
+    deposits: [F; K],
+    start: u64,
+    empty_leaf: F,
+    old_root: F,
+    new_root: F,
+    _spec: PhantomData<S>,
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize, const K: usize>
+    DepositRangeCircuit<F, S, WIDTH, RATE, N, K>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Clone,
+{
+    /// `K`, checked at compile time to be non-zero.
+    const DEPOSITS: usize = {
+        assert!(K > 0, "a batch consumes at least one deposit");
+        K
+    };
+
+    /// Appends `deposits` to `tree` and proves the appends.
+    ///
+    /// Nothing is appended unless all of the deposits fit.
+    pub fn new<H: FieldHasher<F, 2>>(
+        tree: &mut IncrementalTree<F, H, N>,
+        deposits: [F; K],
+    ) -> Result<Self, IncrementalError> {
+        if tree.remaining() < Self::DEPOSITS as u64 {
+            return Err(IncrementalError::Full);
+        }
+
+        let start = tree.len();
+        let old_root = tree.root();
+        let mut paths = [Value::unknown(); K];
+        for (path, deposit) in paths.iter_mut().zip(deposits) {
+            *path = Value::known(tree.append(deposit)?.path);
+        }
+
+        Ok(Self {
+            paths,
+            deposits,
+            start,
+            empty_leaf: tree.empty_leaf(),
+            old_root,
+            new_root: tree.root(),
+            _spec: PhantomData,
+        })
+    }
+
+    /// Rows taken by one deposit: the empty and filled root over its path.
+    pub fn rows_per_deposit() -> usize {
+        2 * MerklePathChip::<F, S, WIDTH, RATE>::rows_per_root::<N>()
+    }
+
+    /// The smallest `k` that fits a batch of `K` deposits.
+    pub fn min_k() -> u32 {
+        min_k::<F, Self>(K * Self::rows_per_deposit())
+    }
+
+    pub fn num_instance() -> Vec<usize> {
+        vec![FIRST_DEPOSIT_ROW + K]
+    }
+
+    pub fn instances(&self) -> Vec<Vec<F>> {
+        let mut instances = vec![F::zero(); FIRST_DEPOSIT_ROW + K];
+        instances[OLD_ROOT_ROW] = self.old_root;
+        instances[NEW_ROOT_ROW] = self.new_root;
+        instances[START_ROW] = F::from(self.start);
+        instances[FIRST_DEPOSIT_ROW..].copy_from_slice(&self.deposits);
+        vec![instances]
+    }
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize, const K: usize> Circuit<F>
+    for DepositRangeCircuit<F, S, WIDTH, RATE, N, K>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Clone,
+{
+    type Config = DepositRangeConfig<F, WIDTH, RATE>;
+    type FloorPlanner = SimpleFloorPlanner;
+
+    fn without_witnesses(&self) -> Self {
+        Self {
+            paths: [Value::unknown(); K],
+            deposits: [F::zero(); K],
+            start: 0,
+            empty_leaf: self.empty_leaf,
+            old_root: F::zero(),
+            new_root: F::zero(),
+            _spec: PhantomData,
+        }
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let index = meta.advice_column();
+        meta.enable_equality(index);
+        let instance = meta.instance_column();
+        meta.enable_equality(instance);
+
+        let s_next = meta.selector();
+        meta.create_gate("next index", |meta| {
+            let s_next = meta.query_selector(s_next);
+            let index_prev = meta.query_advice(index, Rotation::prev());
+            let index = meta.query_advice(index, Rotation::cur());
+            vec![s_next * (index - index_prev - Expression::Constant(F::one()))]
+        });
+
+        DepositRangeConfig {
+            path_config: MerklePathChip::<F, S, WIDTH, RATE>::configure(meta),
+            index,
+            s_next,
+            instance,
+        }
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>,
+    ) -> Result<(), Error> {
+        let chip = MerklePathChip::<F, S, WIDTH, RATE>::construct(config.path_config);
+
+        // `start`, `start + 1`, ..., `start + K - 1`, the first copied from
+        // the instance and each next one constrained to be one more.
+        let indices = layouter.assign_region(
+            || "deposit indices",
+            |mut region| {
+                let mut indices = vec![region.assign_advice_from_instance(
+                    || "start",
+                    config.instance,
+                    START_ROW,
+                    config.index,
+                    0,
+                )?];
+                for row in 1..K {
+                    config.s_next.enable(&mut region, row)?;
+                    let next = indices[row - 1].value().map(|index| *index + F::one());
+                    indices.push(region.assign_advice(
+                        || format!("index_{}", row),
+                        config.index,
+                        row,
+                        || next,
+                    )?);
+                }
+                Ok(indices)
+            },
+        )?;
+
+        let mut root = None;
+        for (i, (path, index)) in self.paths.iter().zip(indices.iter()).enumerate() {
+            let mut layouter = layouter.namespace(|| format!("deposit {}", i));
+
+            let deposit = layouter.assign_region(
+                || "load deposit",
+                |mut region| {
+                    region.assign_advice_from_instance(
+                        || "deposit",
+                        config.instance,
+                        FIRST_DEPOSIT_ROW + i,
+                        config.index,
+                        0,
+                    )
+                },
+            )?;
+            let (bits, siblings) = chip.load_path(layouter.namespace(|| "path"), *path)?;
+            chip.constrain_index(layouter.namespace(|| "index"), &bits, index)?;
+
+            let old_root = chip.compute_empty_root(
+                layouter.namespace(|| "old root"),
+                self.empty_leaf,
+                &bits,
+                &siblings,
+            )?;
+            let new_root = chip.compute_root(
+                layouter.namespace(|| "new root"),
+                &deposit,
+                &bits,
+                &siblings,
+            )?;
+
+            match root {
+                None => {
+                    layouter.constrain_instance(old_root.cell(), config.instance, OLD_ROOT_ROW)?
+                }
+                Some(root) => layouter.assign_region(
+                    || "chain",
+                    |mut region| region.constrain_equal(root.cell(), old_root.cell()),
+                )?,
+            }
+            root = Some(new_root);
+        }
+
+        let root = root.expect("a batch consumes at least one deposit");
+        layouter.constrain_instance(root.cell(), config.instance, NEW_ROOT_ROW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use halo2_proofs::arithmetic::Field;
+    use halo2_proofs::dev::MockProver;
+    use halo2_proofs::halo2curves::bn256::Fr;
+    use rand::rngs::OsRng;
+    use smt::poseidon::{Poseidon, SmtP128Pow5T3};
+
+    #[test]
+    fn deposit_range_test() {
+        const HEIGHT: usize = 4;
+        type TestCircuit = DepositRangeCircuit<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2, HEIGHT, 3>;
+
+        let hasher = Poseidon::<Fr, 2>::new();
+        let mut tree = IncrementalTree::<Fr, _, HEIGHT>::new(Fr::zero(), hasher);
+        for _ in 0..5 {
+            tree.append(Fr::random(OsRng)).unwrap();
+        }
+        let deposits = [(); 3].map(|_| Fr::random(OsRng));
+        let mut later = tree.clone();
+
+        let circuit = TestCircuit::new(&mut tree, deposits).unwrap();
+        let instances = circuit.instances();
+        assert_eq!(instances[0][START_ROW], Fr::from(5));
+        assert_eq!(instances[0][NEW_ROOT_ROW], tree.root());
+
+        let k = TestCircuit::min_k();
+        let prover = MockProver::run(k, &circuit, instances.clone()).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        // Deposits cannot be consumed out of order.
+        let mut reordered = instances[0].clone();
+        reordered.swap(FIRST_DEPOSIT_ROW, FIRST_DEPOSIT_ROW + 1);
+        let prover = MockProver::run(k, &circuit, vec![reordered]).unwrap();
+        assert!(prover.verify().is_err());
+
+        // Nor can one be skipped: the same leaves from index 6 give another
+        // root, and claiming index 6 for this witness fails.
+        let mut skipped = instances[0].clone();
+        skipped[START_ROW] = Fr::from(6);
+        let prover = MockProver::run(k, &circuit, vec![skipped]).unwrap();
+        assert!(prover.verify().is_err());
+
+        later.append(Fr::random(OsRng)).unwrap();
+        let skipping = TestCircuit::new(&mut later, deposits).unwrap();
+        assert_ne!(skipping.instances()[0][NEW_ROOT_ROW], tree.root());
+
+        // A batch that overflows the queue appends nothing.
+        let mut nearly_full = later.clone();
+        while nearly_full.remaining() > 2 {
+            nearly_full.append(Fr::random(OsRng)).unwrap();
+        }
+        let root = nearly_full.root();
+        assert!(matches!(
+            TestCircuit::new(&mut nearly_full, deposits),
+            Err(IncrementalError::Full)
+        ));
+        assert_eq!(nearly_full.root(), root);
+    }
+}
@@ -0,0 +1,221 @@
+use halo2_proofs::arithmetic::FieldExt;
+use smt::poseidon::FieldHasher;
+use std::fmt;
+
+use crate::batch::LeafUpdate;
+use crate::path::AuthPath;
+use crate::tree::{empty_hashes, Height};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IncrementalError {
+    /// Every slot but the last is filled.
+    Full,
+    /// A saved length past the capacity of the tree.
+    LengthOutOfRange(u64),
+}
+
+impl fmt::Display for IncrementalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IncrementalError::Full => write!(f, "the tree is full"),
+            IncrementalError::LengthOutOfRange(len) => {
+                write!(f, "{} leaves do not fit the tree", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for IncrementalError {}
+
+/// An append-only Merkle tree of height `N` that keeps only its frontier.
+///
+/// `frontier[level]` is the last complete left node of that level, which is
+/// all that is needed to hash the next leaf up to the root. The tree takes
+/// `O(N)` storage whatever its length, and its root is the root of a
+/// `MerkleTree` holding the same leaves at `0..len`. As in the Ethereum
+/// deposit contract, the last slot is never filled, so the frontier always
+/// determines the root.
+#[derive(Clone, Debug)]
+pub struct IncrementalTree<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> {
+    frontier: [F; N],
+    len: u64,
+    empty: Vec<F>,
+    hasher: H,
+}
+
+impl<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> IncrementalTree<F, H, N> {
+    pub fn new(empty_leaf: F, hasher: H) -> Self {
+        Self {
+            frontier: [F::zero(); N],
+            len: 0,
+            empty: empty_hashes(empty_leaf, &hasher, Height::<N>::CHECKED),
+            hasher,
+        }
+    }
+
+    /// Resumes a tree from a saved frontier and length.
+    pub fn from_frontier(
+        frontier: [F; N],
+        len: u64,
+        empty_leaf: F,
+        hasher: H,
+    ) -> Result<Self, IncrementalError> {
+        if len > Self::capacity() {
+            return Err(IncrementalError::LengthOutOfRange(len));
+        }
+
+        Ok(Self {
+            frontier,
+            len,
+            empty: empty_hashes(empty_leaf, &hasher, Height::<N>::CHECKED),
+            hasher,
+        })
+    }
+
+    /// The number of leaves in a full tree: every slot but the last.
+    pub fn capacity() -> u64 {
+        (1 << Height::<N>::CHECKED) - 1
+    }
+
+    pub fn frontier(&self) -> [F; N] {
+        self.frontier
+    }
+
+    /// The number of leaves appended so far, which is also the index of the
+    /// next one.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// The number of leaves that can still be appended.
+    pub fn remaining(&self) -> u64 {
+        Self::capacity() - self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn empty_leaf(&self) -> F {
+        self.empty[0]
+    }
+
+    /// The siblings of the next, still empty, slot: frontier nodes to its
+    /// left and empty subtrees to its right.
+    pub fn next_path(&self) -> AuthPath<F, N> {
+        let mut siblings = [F::zero(); N];
+        for (level, sibling) in siblings.iter_mut().enumerate() {
+            *sibling = if (self.len >> level) & 1 == 1 {
+                self.frontier[level]
+            } else {
+                self.empty[level]
+            };
+        }
+        AuthPath {
+            siblings,
+            index: self.len,
+        }
+    }
+
+    pub fn root(&self) -> F {
+        self.next_path().root(self.empty_leaf(), &self.hasher)
+    }
+
+    /// Appends `leaf` and returns the insertion into the empty slot at
+    /// `len()`, for an update or deposit circuit.
+    pub fn append(&mut self, leaf: F) -> Result<LeafUpdate<F, N>, IncrementalError> {
+        if self.len >= Self::capacity() {
+            return Err(IncrementalError::Full);
+        }
+
+        let update = LeafUpdate {
+            path: self.next_path(),
+            old_leaf: self.empty_leaf(),
+            new_leaf: leaf,
+        };
+
+        // Climb while the node is a right child; the first left node found is
+        // complete and becomes the frontier of its level.
+        let mut node = leaf;
+        for level in 0..N {
+            if (self.len >> level) & 1 == 0 {
+                self.frontier[level] = node;
+                break;
+            }
+            node = self.hasher.hash([self.frontier[level], node]).unwrap();
+        }
+        self.len += 1;
+        Ok(update)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MerkleTree;
+    use halo2_proofs::arithmetic::Field;
+    use halo2_proofs::halo2curves::bn256::Fr;
+    use rand::rngs::OsRng;
+    use smt::poseidon::Poseidon;
+
+    #[test]
+    fn incremental_tree_test() {
+        const HEIGHT: usize = 4;
+
+        let hasher = Poseidon::<Fr, 2>::new();
+        let mut incremental = IncrementalTree::<Fr, _, HEIGHT>::new(Fr::zero(), hasher.clone());
+        let mut tree = MerkleTree::<Fr, _, HEIGHT>::new(Fr::zero(), hasher.clone());
+        assert_eq!(incremental.root(), tree.root().unwrap());
+
+        for index in 0..(1 << HEIGHT) - 1 {
+            let leaf = Fr::random(OsRng);
+            let update = incremental.append(leaf).unwrap();
+            assert_eq!(update, tree.update(index, leaf).unwrap());
+            assert_eq!(incremental.root(), tree.root().unwrap());
+        }
+        assert_eq!(incremental.len(), (1 << HEIGHT) - 1);
+
+        let resumed = IncrementalTree::<Fr, _, HEIGHT>::from_frontier(
+            incremental.frontier(),
+            incremental.len(),
+            Fr::zero(),
+            hasher,
+        )
+        .unwrap();
+        assert_eq!(resumed.root(), incremental.root());
+    }
+
+    #[test]
+    fn full_tree_test() {
+        const HEIGHT: usize = 2;
+        type Tree = IncrementalTree<Fr, Poseidon<Fr, 2>, HEIGHT>;
+
+        let hasher = Poseidon::<Fr, 2>::new();
+        let mut full = Tree::new(Fr::zero(), hasher.clone());
+        for _ in 0..Tree::capacity() {
+            full.append(Fr::random(OsRng)).unwrap();
+        }
+        assert_eq!(full.len(), 3);
+        assert_eq!(full.remaining(), 0);
+
+        // A full tree can be resumed, but neither it nor the original takes
+        // another leaf.
+        let mut resumed =
+            Tree::from_frontier(full.frontier(), full.len(), Fr::zero(), hasher.clone()).unwrap();
+        assert_eq!(resumed.root(), full.root());
+        assert!(matches!(
+            resumed.append(Fr::one()),
+            Err(IncrementalError::Full)
+        ));
+        assert!(matches!(
+            full.append(Fr::one()),
+            Err(IncrementalError::Full)
+        ));
+
+        // Nor can a length past capacity be resumed.
+        assert!(matches!(
+            Tree::from_frontier(full.frontier(), 1 << HEIGHT, Fr::zero(), hasher),
+            Err(IncrementalError::LengthOutOfRange(4))
+        ));
+    }
+}
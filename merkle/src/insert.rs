@@ -0,0 +1,212 @@
+use halo2_gadgets::poseidon::primitives::Spec;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner, Value};
+use halo2_proofs::plonk::{Circuit, ConstraintSystem, Error};
+use smt::poseidon::FieldHasher;
+use smt::smt::Path;
+use std::marker::PhantomData;
+
+use crate::path::AuthPath;
+use crate::update::{MerkleUpdateConfig, NEW_ROOT_ROW, OLD_ROOT_ROW};
+
+/// Shows that the slot at the path's index holds `empty_leaf`, i.e. that no
+/// account has been inserted there yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NonMembershipProof<F: FieldExt, const N: usize> {
+    pub path: AuthPath<F, N>,
+}
+
+impl<F: FieldExt, const N: usize> NonMembershipProof<F, N> {
+    /// Takes the path of the slot at `index` out of an `smt` path.
+    ///
+    /// Returns `None` if the slot does not hold `empty_leaf`.
+    pub fn from_smt<H: FieldHasher<F, 2>>(
+        path: &Path<F, H, N>,
+        index: u64,
+        empty_leaf: F,
+    ) -> Option<Self> {
+        let (left, right) = path.path[0];
+        let leaf = if index & 1 == 1 { right } else { left };
+        (leaf == empty_leaf).then(|| Self {
+            path: AuthPath::from_smt(path, index),
+        })
+    }
+
+    pub fn index(&self) -> u64 {
+        self.path.index
+    }
+
+    /// Checks that `empty_leaf` at the proof's index hashes up to `root`.
+    pub fn verify<H: FieldHasher<F, 2>>(&self, root: F, empty_leaf: F, hasher: &H) -> bool {
+        self.path.root(empty_leaf, hasher) == root
+    }
+}
+
+/// Proves that writing `new_leaf` into an empty slot moves the tree from the
+/// root at `OLD_ROOT_ROW` to the root at `NEW_ROOT_ROW`.
+///
+/// The old root is computed from `empty_leaf` as a circuit constant, so the
+/// proof also shows the slot was unused before the insertion.
+#[derive(Clone, Debug)]
+pub struct MerkleInsertCircuit<
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+    const WIDTH: usize,
+    const RATE: usize,
+    const N: usize,
+> {
+    path: Value<AuthPath<F, N>>,
+    new_leaf: Value<F>,
+    empty_leaf: F,
+    old_root: F,
+    new_root: F,
+    _spec: PhantomData<S>,
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize>
+    MerkleInsertCircuit<F, S, WIDTH, RATE, N>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+{
+    pub fn new<H: FieldHasher<F, 2>>(
+        proof: NonMembershipProof<F, N>,
+        empty_leaf: F,
+        new_leaf: F,
+        hasher: &H,
+    ) -> Self {
+        Self {
+            path: Value::known(proof.path),
+            new_leaf: Value::known(new_leaf),
+            empty_leaf,
+            old_root: proof.path.root(empty_leaf, hasher),
+            new_root: proof.path.root(new_leaf, hasher),
+            _spec: PhantomData,
+        }
+    }
+
+    pub fn num_instance() -> Vec<usize> {
+        vec![2]
+    }
+
+    pub fn instances(&self) -> Vec<Vec<F>> {
+        let mut instances = vec![F::zero(); 2];
+        instances[OLD_ROOT_ROW] = self.old_root;
+        instances[NEW_ROOT_ROW] = self.new_root;
+        vec![instances]
+    }
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize> Circuit<F>
+    for MerkleInsertCircuit<F, S, WIDTH, RATE, N>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Clone,
+{
+    type Config = MerkleUpdateConfig<F, WIDTH, RATE>;
+    type FloorPlanner = SimpleFloorPlanner;
+
+    fn without_witnesses(&self) -> Self {
+        Self {
+            path: Value::unknown(),
+            new_leaf: Value::unknown(),
+            empty_leaf: self.empty_leaf,
+            old_root: F::zero(),
+            new_root: F::zero(),
+            _spec: PhantomData,
+        }
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        MerkleUpdateConfig::configure::<S>(meta)
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>,
+    ) -> Result<(), Error> {
+        let chip = config.chip::<S>();
+
+        let new_leaf = layouter.assign_region(
+            || "load new leaf",
+            |mut region| region.assign_advice(|| "new leaf", config.leaves, 0, || self.new_leaf),
+        )?;
+        let (bits, siblings) = chip.load_path(layouter.namespace(|| "path"), self.path)?;
+
+        let old_root = chip.compute_empty_root(
+            layouter.namespace(|| "old root"),
+            self.empty_leaf,
+            &bits,
+            &siblings,
+        )?;
+        let new_root = chip.compute_root(
+            layouter.namespace(|| "new root"),
+            &new_leaf,
+            &bits,
+            &siblings,
+        )?;
+
+        layouter.constrain_instance(old_root.cell(), config.roots, OLD_ROOT_ROW)?;
+        layouter.constrain_instance(new_root.cell(), config.roots, NEW_ROOT_ROW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use halo2_proofs::arithmetic::Field;
+    use halo2_proofs::dev::MockProver;
+    use halo2_proofs::halo2curves::bn256::Fr;
+    use rand::rngs::OsRng;
+    use smt::poseidon::{Poseidon, SmtP128Pow5T3};
+    use smt::smt::SparseMerkleTree;
+
+    #[test]
+    fn merkle_insert_test() {
+        const HEIGHT: usize = 3;
+        const K: u32 = 10;
+        type TestCircuit = MerkleInsertCircuit<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2, HEIGHT>;
+
+        let hasher = Poseidon::<Fr, 2>::new();
+        let mut leaves: Vec<Fr> = (0..6).map(|_| Fr::random(OsRng)).collect();
+        let tree = |leaves: &[Fr]| {
+            SparseMerkleTree::<Fr, Poseidon<Fr, 2>, HEIGHT>::new_sequential(
+                leaves, &hasher, &[0u8; 64],
+            )
+            .unwrap()
+        };
+        let smt = tree(&leaves);
+
+        // Slots 6 and 7 are unused, so they share the stored empty leaf.
+        let empty_leaf = smt.generate_membership_proof(7).path[0].0;
+        let proof =
+            NonMembershipProof::from_smt(&smt.generate_membership_proof(6), 6, empty_leaf).unwrap();
+        assert!(proof.verify(smt.root(), empty_leaf, &hasher));
+        assert!(
+            NonMembershipProof::from_smt(&smt.generate_membership_proof(5), 5, empty_leaf)
+                .is_none()
+        );
+
+        let new_leaf = Fr::random(OsRng);
+        leaves.push(new_leaf);
+        let circuit = TestCircuit::new(proof, empty_leaf, new_leaf, &hasher);
+        let instances = circuit.instances();
+        assert_eq!(instances[0][OLD_ROOT_ROW], smt.root());
+        assert_eq!(instances[0][NEW_ROOT_ROW], tree(&leaves).root());
+
+        let prover = MockProver::run(K, &circuit, instances).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        // An occupied slot cannot be passed off as empty.
+        let occupied = NonMembershipProof {
+            path: AuthPath::from_smt(&smt.generate_membership_proof(5), 5),
+        };
+        assert!(!occupied.verify(smt.root(), empty_leaf, &hasher));
+        let circuit = TestCircuit::new(occupied, empty_leaf, new_leaf, &hasher);
+        let mut instances = circuit.instances();
+        instances[0][OLD_ROOT_ROW] = smt.root();
+        let prover = MockProver::run(K, &circuit, instances).unwrap();
+        assert!(prover.verify().is_err());
+    }
+}
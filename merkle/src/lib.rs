@@ -1,76 +1,120 @@
-use chiplet::smt_chip::{PathChip, PathConfig};
-use chiplet::utilities::{AssertEqualChip, AssertEqualConfig};
 use smt::poseidon::FieldHasher;
-use smt::smt::SparseMerkleTree;
+use smt::smt::{Path, SparseMerkleTree};
 use std::marker::PhantomData;
 
-use halo2_curves::bn256::Fr;
 use halo2_gadgets::poseidon::primitives::Spec;
 use halo2_proofs::arithmetic::FieldExt;
 use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner, Value};
-use halo2_proofs::plonk::{Advice, Circuit, Column, ConstraintSystem, Error};
+use halo2_proofs::plonk::{Advice, Circuit, Column, ConstraintSystem, Error, Instance};
 
-#[derive(Clone)]
-pub struct MerkleConfig<
-    F: FieldExt,
-    S: Spec<F, WIDTH, RATE>,
-    H: FieldHasher<F, 2>,
-    const WIDTH: usize,
-    const RATE: usize,
-    const N: usize,
-> {
-    path_config: PathConfig<F, S, WIDTH, RATE, N>,
-    advices: [Column<Advice>; 3],
-    assert_equal_config: AssertEqualConfig<F>,
-    _hasher: PhantomData<H>,
+mod address;
+mod batch;
+mod deposit;
+mod incremental;
+mod insert;
+mod multiproof;
+mod path;
+mod proof;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod store;
+mod tree;
+mod update;
+
+pub use address::{AddressPath, AddressTree, AddressUpdate, AddressUpdateCircuit, ADDRESS_ROW};
+pub use batch::{BatchError, LeafUpdate, MerkleBatchUpdateCircuit};
+pub use deposit::{DepositRangeCircuit, DepositRangeConfig, FIRST_DEPOSIT_ROW, START_ROW};
+pub use incremental::{IncrementalError, IncrementalTree};
+pub use insert::{MerkleInsertCircuit, NonMembershipProof};
+pub use multiproof::{
+    MerkleMultiProofCircuit, MerkleMultiProofConfig, MultiProof, MultiProofError,
+};
+pub use path::{AuthPath, MerklePathChip, MerklePathConfig, PathError};
+pub use proof::{root_from_bytes, verify_proof, Proof, ProofError, PROOF_VERSION};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+pub use store::{
+    AddressNodeChange, AddressStore, FileStore, MemoryStore, NodeChange, NodeStore, StoreError,
+};
+pub use tree::{MerkleTree, TreeError};
+pub use update::{MerkleUpdateCircuit, MerkleUpdateConfig, NEW_ROOT_ROW, OLD_ROOT_ROW};
+
+use tree::Height;
+
+pub const ROOT_ROW: usize = 0;
+pub const LEAF_ROW: usize = 1;
+
+/// Rows left to the layout of `C` at `k` once its blinding rows are reserved.
+pub fn usable_rows<F: FieldExt, C: Circuit<F>>(k: u32) -> usize {
+    let mut meta = ConstraintSystem::default();
+    C::configure(&mut meta);
+    (1usize << k).saturating_sub(meta.blinding_factors() + 1)
+}
+
+/// The smallest `k` at which `C` has `rows` usable rows.
+pub fn min_k<F: FieldExt, C: Circuit<F>>(rows: usize) -> u32 {
+    (1..)
+        .find(|&k| usable_rows::<F, C>(k) >= rows)
+        .expect("some k fits the rows")
+}
+
+#[derive(Clone, Debug)]
+pub struct MerkleConfig<F: FieldExt, const WIDTH: usize, const RATE: usize> {
+    path_config: MerklePathConfig<F, WIDTH, RATE>,
+    leaf: Column<Advice>,
+    instance: Column<Instance>,
 }
 
-#[derive(Clone)]
+/// Proves that `leaf` sits at the index of its authentication path.
+///
+/// The root is exposed at instance row `ROOT_ROW` and the leaf at `LEAF_ROW`,
+/// so a verifier can bind the proof to an on-chain root. The circuit only
+/// holds the path: the tree is built once, outside the circuit, and never
+/// rebuilt during keygen or proving.
+#[derive(Clone, Debug)]
 pub struct MerkleCircuit<
     F: FieldExt,
     S: Spec<F, WIDTH, RATE>,
-    H: FieldHasher<F, 2>,
     const WIDTH: usize,
     const RATE: usize,
     const N: usize,
 > {
-    leaves: [F; 3],
-    empty_leaf: [u8; 64],
-    hasher: H,
+    path: Value<AuthPath<F, N>>,
+    leaf: F,
+    index: u64,
+    root: F,
     _spec: PhantomData<S>,
 }
-impl<
-        F: FieldExt,
-        S: Spec<F, WIDTH, RATE> + Clone,
-        H: FieldHasher<F, 2> + Clone,
-        const WIDTH: usize,
-        const RATE: usize,
-        const N: usize,
-    > Circuit<F> for MerkleCircuit<F, S, H, WIDTH, RATE, N>
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize> Circuit<F>
+    for MerkleCircuit<F, S, WIDTH, RATE, N>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Clone,
 {
-    type Config = MerkleConfig<F, S, H, WIDTH, RATE, N>;
+    type Config = MerkleConfig<F, WIDTH, RATE>;
     type FloorPlanner = SimpleFloorPlanner;
 
     fn without_witnesses(&self) -> Self {
         Self {
-            leaves: [F::zero(), F::zero(), F::zero()],
-            empty_leaf: [0u8; 64],
-            hasher: H::hasher(),
+            path: Value::unknown(),
+            leaf: F::zero(),
+            index: 0,
+            root: F::zero(),
             _spec: PhantomData,
         }
     }
 
     fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
-        let advices = [(); 3].map(|_| meta.advice_column());
-        advices
-            .iter()
-            .for_each(|column| meta.enable_equality(*column));
+        let leaf = meta.advice_column();
+        meta.enable_equality(leaf);
+        let instance = meta.instance_column();
+        meta.enable_equality(instance);
 
         MerkleConfig {
-            path_config: PathChip::<F, S, H, WIDTH, RATE, N>::configure(meta),
-            advices,
-            assert_equal_config: AssertEqualChip::configure(meta, [advices[0], advices[1]]),
-            _hasher: PhantomData,
+            path_config: MerklePathChip::<F, S, WIDTH, RATE>::configure(meta),
+            leaf,
+            instance,
         }
     }
 
@@ -79,89 +123,138 @@ impl<
         config: Self::Config,
         mut layouter: impl Layouter<F>,
     ) -> Result<(), Error> {
-        let smt = SparseMerkleTree::<F, H, N>::new_sequential(
-            &self.leaves,
-            &self.hasher.clone(),
-            &self.empty_leaf,
-        )
-        .unwrap();
-        let path = smt.generate_membership_proof(0);
-        let root = path
-            .calculate_root(&self.leaves[0], &self.hasher.clone())
-            .unwrap();
+        let chip = MerklePathChip::<F, S, WIDTH, RATE>::construct(config.path_config);
 
-        let (root_cell, leaf_cell, one) = layouter.assign_region(
-            || "test circuit",
+        let leaf = layouter.assign_region(
+            || "load leaf",
             |mut region| {
-                let root_cell =
-                    region.assign_advice(|| "root", config.advices[0], 0, || Value::known(root))?;
-
-                let leaf_cell = region.assign_advice(
-                    || "leaf",
-                    config.advices[1],
-                    0,
-                    || Value::known(self.leaves[0]),
-                )?;
-
-                let one = region.assign_advice(
-                    || "one",
-                    config.advices[2],
-                    0,
-                    || Value::known(F::one()),
-                )?;
-                Ok((root_cell, leaf_cell, one))
+                region.assign_advice(|| "leaf", config.leaf, 0, || Value::known(self.leaf))
             },
         )?;
+        let (bits, siblings) = chip.load_path(layouter.namespace(|| "path"), self.path)?;
+        let root = chip.compute_root(layouter.namespace(|| "root"), &leaf, &bits, &siblings)?;
 
-        let path_chip = PathChip::<F, S, H, WIDTH, RATE, N>::from_native(
-            config.path_config,
-            &mut layouter,
-            path,
-        )?;
-        let res = path_chip.check_membership(&mut layouter, root_cell, leaf_cell)?;
-
-        let assert_equal_chip = AssertEqualChip::construct(config.assert_equal_config, ());
-        assert_equal_chip.assert_equal(&mut layouter, res, one)?;
-
-        Ok(())
+        layouter.constrain_instance(root.cell(), config.instance, ROOT_ROW)?;
+        layouter.constrain_instance(leaf.cell(), config.instance, LEAF_ROW)
     }
 }
 
-impl<
-        F: FieldExt,
-        S: Spec<F, WIDTH, RATE> + Clone,
-        H: FieldHasher<F, 2> + Clone,
-        const WIDTH: usize,
-        const RATE: usize,
-        const N: usize,
-    > MerkleCircuit<F, S, H, WIDTH, RATE, N>
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize>
+    MerkleCircuit<F, S, WIDTH, RATE, N>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
 {
-    pub fn new(leaves: [F; 3], empty_leaf: [u8; 64], hasher: H) -> Self {
+    /// Proves membership of `leaves[0]` in the tree built from `leaves`.
+    pub fn new<H: FieldHasher<F, 2>>(
+        leaves: [F; 3],
+        empty_leaf: [u8; 64],
+        hasher: H,
+    ) -> Result<Self, PathError> {
+        Self::from_leaves(&leaves, 0, &empty_leaf, hasher)
+    }
+
+    /// Builds the tree of `leaves` to take the path of `leaves[index]`.
+    pub fn from_leaves<H: FieldHasher<F, 2>>(
+        leaves: &[F],
+        index: u64,
+        empty_leaf: &[u8; 64],
+        hasher: H,
+    ) -> Result<Self, PathError> {
+        let leaf = match usize::try_from(index).ok().and_then(|i| leaves.get(i)) {
+            Some(leaf) => *leaf,
+            None => return Err(PathError::IndexOutOfRange(index)),
+        };
+        let smt = SparseMerkleTree::<F, H, N>::new_sequential(leaves, &hasher, empty_leaf)
+            .map_err(|_| PathError::InvalidLeaves)?;
+        let path = smt.generate_membership_proof(index);
+        Self::from_path(path, leaf, index, hasher)
+    }
+
+    /// Takes a path computed outside the circuit, e.g. from a stored tree, and
+    /// checks that it opens `leaf` at `index`.
+    pub fn from_path<H: FieldHasher<F, 2>>(
+        path: Path<F, H, N>,
+        leaf: F,
+        index: u64,
+        hasher: H,
+    ) -> Result<Self, PathError> {
+        if index >> Height::<N>::CHECKED != 0 {
+            return Err(PathError::IndexOutOfRange(index));
+        }
+
+        // Bit `i` of the index tells whether the node at level `i` is a right child.
+        let mut node = leaf;
+        for (level, (left, right)) in path.path.iter().enumerate() {
+            let expected = if (index >> level) & 1 == 1 {
+                right
+            } else {
+                left
+            };
+            if node != *expected {
+                return Err(PathError::LeafMismatch(index));
+            }
+            node = hasher.hash([*left, *right]).unwrap();
+        }
+
+        Ok(Self::from_auth_path(
+            AuthPath::from_smt(&path, index),
+            leaf,
+            &hasher,
+        ))
+    }
+
+    /// Takes only the siblings and index of `leaf`; nothing else of the tree
+    /// is needed to prove membership.
+    pub fn from_auth_path<H: FieldHasher<F, 2>>(path: AuthPath<F, N>, leaf: F, hasher: &H) -> Self {
         Self {
-            leaves,
-            empty_leaf,
-            hasher,
+            path: Value::known(path),
+            leaf,
+            index: path.index,
+            root: path.root(leaf, hasher),
             _spec: PhantomData,
         }
     }
 
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Rows taken by the layout: one root computation over `N` levels.
+    pub fn rows() -> usize {
+        MerklePathChip::<F, S, WIDTH, RATE>::rows_per_root::<N>()
+    }
+
+    /// The smallest `k` that fits a path of height `N`.
+    pub fn min_k() -> u32
+    where
+        S: Clone,
+    {
+        min_k::<F, Self>(Self::rows())
+    }
+
     pub fn num_instance() -> Vec<usize> {
-        vec![]
+        vec![2]
     }
 
-    pub fn instances(&self) -> Vec<Vec<Fr>> {
-        vec![]
+    pub fn instances(&self) -> Vec<Vec<F>> {
+        let mut instances = vec![F::zero(); 2];
+        instances[ROOT_ROW] = self.root;
+        instances[LEAF_ROW] = self.leaf;
+        vec![instances]
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{MerkleCircuit, PhantomData};
+    use super::{AuthPath, MerkleCircuit, PathError, LEAF_ROW, ROOT_ROW};
+    use smt::smt::SparseMerkleTree;
 
     use rand::rngs::OsRng;
     use smt::poseidon::{Poseidon, SmtP128Pow5T3};
 
     use halo2_proofs::arithmetic::Field;
+    use halo2_proofs::dev::MockProver;
     use halo2_proofs::halo2curves::bn256::{Bn256, Fr, G1Affine};
     use halo2_proofs::plonk::{create_proof, keygen_pk, keygen_vk, verify_proof};
     use halo2_proofs::poly::commitment::ParamsProver;
@@ -183,12 +276,15 @@ mod test {
         let leaves = [Fr::random(rng), Fr::random(rng), Fr::random(rng)];
         const HEIGHT: usize = 3;
 
-        let circuit = MerkleCircuit::<Fr, SmtP128Pow5T3<Fr, 0>, Poseidon<Fr, 2>, 3, 2, HEIGHT> {
+        let circuit = MerkleCircuit::<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2, HEIGHT>::new(
             leaves,
             empty_leaf,
-            hasher: Poseidon::<Fr, 2>::new(),
-            _spec: PhantomData,
-        };
+            Poseidon::<Fr, 2>::new(),
+        )
+        .unwrap();
+
+        let instances = circuit.instances();
+        let instance = instances[0].as_slice();
 
         let params: ParamsKZG<Bn256> = ParamsKZG::new(k);
         let vk = keygen_vk(&params, &circuit).expect("keygen_vk should not fail");
@@ -202,19 +298,94 @@ mod test {
             _,
             Blake2bWrite<Vec<u8>, G1Affine, Challenge255<_>>,
             _,
-        >(&params, &pk, &[circuit], &[&[]], OsRng, &mut transcript)
+        >(
+            &params,
+            &pk,
+            &[circuit],
+            &[&[instance]],
+            OsRng,
+            &mut transcript,
+        )
         .expect("proof generation should not fail");
         let proof: Vec<u8> = transcript.finalize();
 
-        let strategy = SingleStrategy::new(&params);
-        let mut transcript = Blake2bRead::<_, _, Challenge255<_>>::init(&proof[..]);
-        let result = verify_proof::<
-            KZGCommitmentScheme<Bn256>,
-            VerifierGWC<'_, Bn256>,
-            Challenge255<G1Affine>,
-            Blake2bRead<&[u8], G1Affine, Challenge255<G1Affine>>,
-            SingleStrategy<'_, Bn256>,
-        >(&params, pk.get_vk(), strategy, &[&[]], &mut transcript);
-        assert!(result.is_ok());
+        let verify = |instance: &[Fr]| {
+            let strategy = SingleStrategy::new(&params);
+            let mut transcript = Blake2bRead::<_, _, Challenge255<_>>::init(&proof[..]);
+            verify_proof::<
+                KZGCommitmentScheme<Bn256>,
+                VerifierGWC<'_, Bn256>,
+                Challenge255<G1Affine>,
+                Blake2bRead<&[u8], G1Affine, Challenge255<G1Affine>>,
+                SingleStrategy<'_, Bn256>,
+            >(
+                &params,
+                pk.get_vk(),
+                strategy,
+                &[&[instance]],
+                &mut transcript,
+            )
+        };
+        assert!(verify(instance).is_ok());
+
+        // The proof does not verify against any other root.
+        let mut wrong_root = instances[0].clone();
+        wrong_root[ROOT_ROW] += Fr::one();
+        assert!(verify(&wrong_root).is_err());
+    }
+
+    #[test]
+    fn arbitrary_index_test() {
+        const HEIGHT: usize = 3;
+        type TestCircuit = MerkleCircuit<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2, HEIGHT>;
+
+        let empty_leaf = [0u8; 64];
+        let hasher = Poseidon::<Fr, 2>::new();
+        let leaves: Vec<Fr> = (0..6).map(|_| Fr::random(OsRng)).collect();
+
+        // The path is computed from a tree held outside the circuit.
+        let smt = SparseMerkleTree::<Fr, Poseidon<Fr, 2>, HEIGHT>::new_sequential(
+            &leaves,
+            &hasher,
+            &empty_leaf,
+        )
+        .unwrap();
+        let path = smt.generate_membership_proof(5);
+        let circuit = TestCircuit::from_path(path, leaves[5], 5, hasher.clone()).unwrap();
+
+        let instances = circuit.instances();
+        assert_eq!(instances[0][ROOT_ROW], smt.root());
+        assert_eq!(instances[0][LEAF_ROW], leaves[5]);
+
+        // Only the siblings are needed, not the tree they came from.
+        let siblings = AuthPath::from_smt(&smt.generate_membership_proof(5), 5);
+        let from_siblings = TestCircuit::from_auth_path(siblings, leaves[5], &hasher);
+        assert_eq!(from_siblings.instances(), instances);
+
+        let prover = MockProver::run(13, &circuit, instances.clone()).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        let mut wrong_leaf = instances[0].clone();
+        wrong_leaf[LEAF_ROW] = leaves[4];
+        let prover = MockProver::run(13, &circuit, vec![wrong_leaf]).unwrap();
+        assert!(prover.verify().is_err());
+
+        // A path for one index cannot be passed off as another.
+        let path = smt.generate_membership_proof(5);
+        assert_eq!(
+            TestCircuit::from_path(path, leaves[5], 4, hasher.clone()).err(),
+            Some(PathError::LeafMismatch(4))
+        );
+        let path = smt.generate_membership_proof(5);
+        assert_eq!(
+            TestCircuit::from_path(path, leaves[5], 1 << HEIGHT, hasher.clone()).err(),
+            Some(PathError::IndexOutOfRange(1 << HEIGHT))
+        );
+
+        // An index past the given leaves is an error rather than a panic.
+        assert_eq!(
+            TestCircuit::from_leaves(&leaves, 6, &empty_leaf, hasher).err(),
+            Some(PathError::IndexOutOfRange(6))
+        );
     }
 }
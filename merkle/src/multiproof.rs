@@ -0,0 +1,474 @@
+use halo2_gadgets::poseidon::primitives::Spec;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::circuit::{AssignedCell, Layouter, SimpleFloorPlanner, Value};
+use halo2_proofs::plonk::{Advice, Circuit, Column, ConstraintSystem, Error, Instance};
+use smt::poseidon::FieldHasher;
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::path::{MerklePathChip, MerklePathConfig};
+use crate::store::NodeStore;
+use crate::tree::{Height, MerkleTree, TreeError};
+use crate::{min_k, ROOT_ROW};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MultiProofError {
+    NoIndices,
+    UnsortedIndices,
+    IndexOutOfRange(u64),
+    WrongLeafCount { expected: usize, actual: usize },
+    WrongNodeCount { expected: usize, actual: usize },
+}
+
+impl fmt::Display for MultiProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultiProofError::NoIndices => write!(f, "a multiproof opens at least one leaf"),
+            MultiProofError::UnsortedIndices => {
+                write!(f, "multiproof indices must be strictly increasing")
+            }
+            MultiProofError::IndexOutOfRange(index) => {
+                write!(f, "index {} is outside the tree", index)
+            }
+            MultiProofError::WrongLeafCount { expected, actual } => {
+                write!(f, "expected {} leaves, got {}", expected, actual)
+            }
+            MultiProofError::WrongNodeCount { expected, actual } => {
+                write!(f, "expected {} proof nodes, got {}", expected, actual)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MultiProofError {}
+
+/// Where one input of a hash comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Source {
+    /// The node at this position among those computed one level below.
+    Below(usize),
+    /// The proof node at this position.
+    Node(usize),
+}
+
+/// How the root is rebuilt from the leaves at `indices`: the hashes of each
+/// level as `(left, right)` inputs, and the positions `(level, index)` of the
+/// siblings the proof must supply, in the order it supplies them.
+#[derive(Clone, Debug)]
+pub(crate) struct Layout {
+    pub(crate) levels: Vec<Vec<(Source, Source)>>,
+    pub(crate) siblings: Vec<(usize, u64)>,
+}
+
+impl Layout {
+    /// The layout for `indices` in a tree of height `N`.
+    pub(crate) fn new<const N: usize>(indices: &[u64]) -> Result<Self, MultiProofError> {
+        let height = Height::<N>::CHECKED;
+        if indices.is_empty() {
+            return Err(MultiProofError::NoIndices);
+        }
+        if indices.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(MultiProofError::UnsortedIndices);
+        }
+        if let Some(index) = indices.iter().find(|index| **index >> height != 0) {
+            return Err(MultiProofError::IndexOutOfRange(*index));
+        }
+
+        let mut levels = Vec::with_capacity(height);
+        let mut siblings = vec![];
+        let mut known = indices.to_vec();
+        for level in 0..height {
+            let mut steps = vec![];
+            let mut parents = vec![];
+            let mut i = 0;
+            while i < known.len() {
+                let index = known[i];
+                let step = if index & 1 == 1 {
+                    siblings.push((level, index ^ 1));
+                    (Source::Node(siblings.len() - 1), Source::Below(i))
+                } else if known.get(i + 1) == Some(&(index + 1)) {
+                    // Both children are known, so neither comes from the proof.
+                    i += 1;
+                    (Source::Below(i - 1), Source::Below(i))
+                } else {
+                    siblings.push((level, index ^ 1));
+                    (Source::Below(i), Source::Node(siblings.len() - 1))
+                };
+                steps.push(step);
+                parents.push(index >> 1);
+                i += 1;
+            }
+            levels.push(steps);
+            known = parents;
+        }
+        Ok(Self { levels, siblings })
+    }
+
+    pub(crate) fn num_hashes(&self) -> usize {
+        self.levels.iter().map(Vec::len).sum()
+    }
+
+    /// Hashes `leaves` up to the root, taking siblings from `nodes`.
+    pub(crate) fn fold<T: Clone, E>(
+        &self,
+        leaves: &[T],
+        nodes: &[T],
+        mut hash: impl FnMut(usize, T, T) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut below = leaves.to_vec();
+        for (level, steps) in self.levels.iter().enumerate() {
+            let pick = |source: Source| match source {
+                Source::Below(i) => below[i].clone(),
+                Source::Node(i) => nodes[i].clone(),
+            };
+            let mut computed = Vec::with_capacity(steps.len());
+            for (left, right) in steps {
+                computed.push(hash(level, pick(*left), pick(*right))?);
+            }
+            below = computed;
+        }
+        Ok(below.remove(0))
+    }
+}
+
+/// Opens several leaves of a height-`N` tree at once.
+///
+/// Siblings shared by the opened paths, or computable from the opened leaves,
+/// are left out, so a dense set of indices needs far fewer nodes and hashes
+/// than one `AuthPath` per leaf.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultiProof<F: FieldExt, const N: usize> {
+    /// Strictly increasing leaf indices.
+    pub indices: Vec<u64>,
+    /// The siblings not computable from the opened leaves, leaf level first
+    /// and left to right within a level.
+    pub nodes: Vec<F>,
+}
+
+impl<F: FieldExt, const N: usize> MultiProof<F, N> {
+    /// Hashes `leaves`, given in index order, up to the root.
+    pub fn root<H: FieldHasher<F, 2>>(
+        &self,
+        leaves: &[F],
+        hasher: &H,
+    ) -> Result<F, MultiProofError> {
+        let layout = self.layout()?;
+        if leaves.len() != self.indices.len() {
+            return Err(MultiProofError::WrongLeafCount {
+                expected: self.indices.len(),
+                actual: leaves.len(),
+            });
+        }
+        layout.fold(leaves, &self.nodes, |_, left, right| {
+            Ok(hasher.hash([left, right]).unwrap())
+        })
+    }
+
+    pub fn verify<H: FieldHasher<F, 2>>(&self, root: F, leaves: &[F], hasher: &H) -> bool {
+        self.root(leaves, hasher) == Ok(root)
+    }
+
+    /// The number of hashes to rebuild the root, against `N` per leaf for
+    /// separate paths.
+    pub fn num_hashes(&self) -> Result<usize, MultiProofError> {
+        Ok(self.layout()?.num_hashes())
+    }
+
+    pub(crate) fn layout(&self) -> Result<Layout, MultiProofError> {
+        let layout = Layout::new::<N>(&self.indices)?;
+        if layout.siblings.len() != self.nodes.len() {
+            return Err(MultiProofError::WrongNodeCount {
+                expected: layout.siblings.len(),
+                actual: self.nodes.len(),
+            });
+        }
+        Ok(layout)
+    }
+}
+
+impl<F, H, const N: usize, S> MerkleTree<F, H, N, S>
+where
+    F: FieldExt,
+    H: FieldHasher<F, 2>,
+    S: NodeStore<F>,
+{
+    /// Opens the leaves at `indices`, which must be strictly increasing and
+    /// inside the tree.
+    pub fn multiproof(&self, indices: &[u64]) -> Result<MultiProof<F, N>, TreeError<S::Error>> {
+        let layout = Layout::new::<N>(indices).map_err(TreeError::MultiProof)?;
+        let nodes = layout
+            .siblings
+            .iter()
+            .map(|(level, index)| self.node(*level, *index))
+            .collect::<Result<_, _>>()?;
+        Ok(MultiProof {
+            indices: indices.to_vec(),
+            nodes,
+        })
+    }
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize> MerklePathChip<F, S, WIDTH, RATE>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+{
+    /// Returns the cell holding the root of `leaves` at `indices`, with the
+    /// remaining siblings taken from `nodes` as laid out by `MultiProof`.
+    ///
+    /// The indices shape the circuit, so no swap regions are needed, but a
+    /// verifying key only holds for the index set it was generated with.
+    pub fn compute_multiproof_root<const N: usize>(
+        &self,
+        layouter: impl Layouter<F>,
+        indices: &[u64],
+        leaves: &[AssignedCell<F, F>],
+        nodes: &[AssignedCell<F, F>],
+    ) -> Result<AssignedCell<F, F>, Error> {
+        let layout = Layout::new::<N>(indices).map_err(|_| Error::Synthesis)?;
+        if leaves.len() != indices.len() || nodes.len() != layout.siblings.len() {
+            return Err(Error::Synthesis);
+        }
+        self.fold_multiproof(layouter, &layout, leaves, nodes)
+    }
+
+    /// `compute_multiproof_root` over a layout whose leaf and node counts
+    /// have already been checked.
+    fn fold_multiproof(
+        &self,
+        mut layouter: impl Layouter<F>,
+        layout: &Layout,
+        leaves: &[AssignedCell<F, F>],
+        nodes: &[AssignedCell<F, F>],
+    ) -> Result<AssignedCell<F, F>, Error> {
+        let mut step = 0;
+        layout.fold(leaves, nodes, |level, left, right| {
+            step += 1;
+            self.hash(
+                layouter.namespace(|| format!("level {} hash {}", level, step)),
+                left,
+                right,
+            )
+        })
+    }
+}
+
+/// Proves that the public leaves at `indices` are in the tree whose root is
+/// at `ROOT_ROW`; the leaves follow the root in index order.
+#[derive(Clone, Debug)]
+pub struct MerkleMultiProofCircuit<
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+    const WIDTH: usize,
+    const RATE: usize,
+    const N: usize,
+> {
+    indices: Vec<u64>,
+    layout: Layout,
+    leaves: Vec<F>,
+    nodes: Vec<Value<F>>,
+    root: F,
+    _spec: PhantomData<S>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MerkleMultiProofConfig<F: FieldExt, const WIDTH: usize, const RATE: usize> {
+    path_config: MerklePathConfig<F, WIDTH, RATE>,
+    advice: Column<Advice>,
+    instance: Column<Instance>,
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize>
+    MerkleMultiProofCircuit<F, S, WIDTH, RATE, N>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Clone,
+{
+    pub fn new<H: FieldHasher<F, 2>>(
+        proof: &MultiProof<F, N>,
+        leaves: &[F],
+        hasher: &H,
+    ) -> Result<Self, MultiProofError> {
+        Ok(Self {
+            root: proof.root(leaves, hasher)?,
+            indices: proof.indices.clone(),
+            layout: proof.layout()?,
+            leaves: leaves.to_vec(),
+            nodes: proof.nodes.iter().map(|node| Value::known(*node)).collect(),
+            _spec: PhantomData,
+        })
+    }
+
+    /// Rows taken by the layout: one Poseidon hash per rebuilt node.
+    pub fn rows(&self) -> usize {
+        self.layout.num_hashes() * MerklePathChip::<F, S, WIDTH, RATE>::rows_per_hash()
+    }
+
+    pub fn min_k(&self) -> u32 {
+        min_k::<F, Self>(self.rows())
+    }
+
+    pub fn num_instance(&self) -> Vec<usize> {
+        vec![1 + self.indices.len()]
+    }
+
+    pub fn instances(&self) -> Vec<Vec<F>> {
+        let mut instances = vec![F::zero(); 1 + self.indices.len()];
+        instances[ROOT_ROW] = self.root;
+        instances[ROOT_ROW + 1..].copy_from_slice(&self.leaves);
+        vec![instances]
+    }
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize> Circuit<F>
+    for MerkleMultiProofCircuit<F, S, WIDTH, RATE, N>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Clone,
+{
+    type Config = MerkleMultiProofConfig<F, WIDTH, RATE>;
+    type FloorPlanner = SimpleFloorPlanner;
+
+    fn without_witnesses(&self) -> Self {
+        Self {
+            indices: self.indices.clone(),
+            layout: self.layout.clone(),
+            leaves: vec![F::zero(); self.leaves.len()],
+            nodes: vec![Value::unknown(); self.nodes.len()],
+            root: F::zero(),
+            _spec: PhantomData,
+        }
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let advice = meta.advice_column();
+        meta.enable_equality(advice);
+        let instance = meta.instance_column();
+        meta.enable_equality(instance);
+
+        MerkleMultiProofConfig {
+            path_config: MerklePathChip::<F, S, WIDTH, RATE>::configure(meta),
+            advice,
+            instance,
+        }
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>,
+    ) -> Result<(), Error> {
+        let chip = MerklePathChip::<F, S, WIDTH, RATE>::construct(config.path_config);
+
+        let (leaves, nodes) = layouter.assign_region(
+            || "load multiproof",
+            |mut region| {
+                let leaves = (0..self.indices.len())
+                    .map(|i| {
+                        region.assign_advice_from_instance(
+                            || format!("leaf_{}", i),
+                            config.instance,
+                            ROOT_ROW + 1 + i,
+                            config.advice,
+                            i,
+                        )
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                let nodes = self
+                    .nodes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, node)| {
+                        region.assign_advice(
+                            || format!("node_{}", i),
+                            config.advice,
+                            leaves.len() + i,
+                            || *node,
+                        )
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Ok((leaves, nodes))
+            },
+        )?;
+
+        let root = chip.fold_multiproof(
+            layouter.namespace(|| "multiproof"),
+            &self.layout,
+            &leaves,
+            &nodes,
+        )?;
+        layouter.constrain_instance(root.cell(), config.instance, ROOT_ROW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use halo2_proofs::arithmetic::Field;
+    use halo2_proofs::dev::MockProver;
+    use halo2_proofs::halo2curves::bn256::Fr;
+    use rand::rngs::OsRng;
+    use smt::poseidon::{Poseidon, SmtP128Pow5T3};
+
+    #[test]
+    fn multiproof_test() {
+        const HEIGHT: usize = 8;
+        type TestCircuit = MerkleMultiProofCircuit<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2, HEIGHT>;
+
+        let hasher = Poseidon::<Fr, 2>::new();
+        let leaves: Vec<Fr> = (0..40).map(|_| Fr::random(OsRng)).collect();
+        let tree =
+            MerkleTree::<Fr, _, HEIGHT>::from_leaves(&leaves, Fr::zero(), hasher.clone()).unwrap();
+        let root = tree.root().unwrap();
+
+        let indices = [2u64, 3, 4, 6, 7, 33, 200];
+        let opened: Vec<Fr> = indices.iter().map(|i| tree.leaf(*i).unwrap()).collect();
+        let proof = tree.multiproof(&indices).unwrap();
+        assert!(proof.verify(root, &opened, &hasher));
+        assert!(proof.nodes.len() < indices.len() * HEIGHT);
+        assert!(proof.num_hashes().unwrap() < indices.len() * HEIGHT);
+
+        let mut tampered = opened.clone();
+        tampered.swap(0, 1);
+        assert!(!proof.verify(root, &tampered, &hasher));
+        let mut short = proof.clone();
+        short.nodes.pop();
+        assert!(matches!(
+            short.root(&opened, &hasher),
+            Err(MultiProofError::WrongNodeCount { .. })
+        ));
+
+        let circuit = TestCircuit::new(&proof, &opened, &hasher).unwrap();
+        let instances = circuit.instances();
+        assert_eq!(instances[0][ROOT_ROW], root);
+
+        let k = circuit.min_k();
+        let prover = MockProver::run(k, &circuit, instances.clone()).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        let mut wrong = instances[0].clone();
+        wrong[ROOT_ROW + 1] = leaves[0];
+        let prover = MockProver::run(k, &circuit, vec![wrong]).unwrap();
+        assert!(prover.verify().is_err());
+
+        // Index sets that do not form a multiproof are errors, not panics.
+        assert_eq!(
+            tree.multiproof(&[]),
+            Err(TreeError::MultiProof(MultiProofError::NoIndices))
+        );
+        assert_eq!(
+            tree.multiproof(&[4, 2]),
+            Err(TreeError::MultiProof(MultiProofError::UnsortedIndices))
+        );
+        assert_eq!(
+            tree.multiproof(&[2, 2]),
+            Err(TreeError::MultiProof(MultiProofError::UnsortedIndices))
+        );
+        assert_eq!(
+            tree.multiproof(&[2, 1 << HEIGHT]),
+            Err(TreeError::MultiProof(MultiProofError::IndexOutOfRange(
+                1 << HEIGHT
+            )))
+        );
+    }
+}
@@ -0,0 +1,512 @@
+use halo2_gadgets::poseidon::{
+    primitives::{ConstantLength, Domain, Spec},
+    PaddedWord, Pow5Chip, Pow5Config, Sponge,
+};
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::circuit::{AssignedCell, Layouter, Value};
+use halo2_proofs::plonk::{Advice, Column, ConstraintSystem, Error, Expression, Selector};
+use halo2_proofs::poly::Rotation;
+use smt::poseidon::FieldHasher;
+use smt::smt::Path;
+use std::array;
+use std::fmt;
+use std::marker::PhantomData;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PathError {
+    IndexOutOfRange(u64),
+    /// The path does not hash up from the leaf at this index.
+    LeafMismatch(u64),
+    /// The leaves could not be built into a tree, e.g. there are more than
+    /// it can hold.
+    InvalidLeaves,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathError::IndexOutOfRange(index) => {
+                write!(f, "index {} is outside the tree", index)
+            }
+            PathError::LeafMismatch(index) => {
+                write!(f, "path does not open the leaf at index {}", index)
+            }
+            PathError::InvalidLeaves => write!(f, "leaves do not form a tree"),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+/// Sibling hashes from the leaf level up, and the leaf index whose bit `i`
+/// is set when the node at level `i` is a right child.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AuthPath<F: FieldExt, const N: usize> {
+    pub siblings: [F; N],
+    pub index: u64,
+}
+
+impl<F: FieldExt, const N: usize> AuthPath<F, N> {
+    /// Takes the siblings of the node at `index` out of an `smt` path.
+    pub fn from_smt<H: FieldHasher<F, 2>>(path: &Path<F, H, N>, index: u64) -> Self {
+        let mut siblings = [F::zero(); N];
+        for (level, (sibling, (left, right))) in
+            siblings.iter_mut().zip(path.path.iter()).enumerate()
+        {
+            *sibling = if Self::is_right(index, level) {
+                *left
+            } else {
+                *right
+            };
+        }
+        Self { siblings, index }
+    }
+
+    fn is_right(index: u64, level: usize) -> bool {
+        (index >> level) & 1 == 1
+    }
+
+    /// Hashes `leaf` up to the root along this path.
+    pub fn root<H: FieldHasher<F, 2>>(&self, leaf: F, hasher: &H) -> F {
+        self.siblings
+            .iter()
+            .enumerate()
+            .fold(leaf, |node, (level, sibling)| {
+                let pair = if Self::is_right(self.index, level) {
+                    [*sibling, node]
+                } else {
+                    [node, *sibling]
+                };
+                hasher.hash(pair).unwrap()
+            })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MerklePathConfig<F: FieldExt, const WIDTH: usize, const RATE: usize> {
+    swap: [Column<Advice>; 3],
+    s_swap: Selector,
+    s_index: Selector,
+    s_max_one: Selector,
+    s_max_zero: Selector,
+    poseidon_config: Pow5Config<F, WIDTH, RATE>,
+}
+
+/// Recomputes a root from a leaf cell and assigned index bits and siblings.
+///
+/// Each level takes a two-row swap region, `[bit, node, sibling]` followed by
+/// `[_, left, right]`, and a width-3 Poseidon hash of `left` and `right`.
+/// Because the bits and siblings are passed in as cells, several roots can be
+/// computed over the same path, e.g. before and after a leaf update.
+#[derive(Clone, Debug)]
+pub struct MerklePathChip<
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+    const WIDTH: usize,
+    const RATE: usize,
+> {
+    config: MerklePathConfig<F, WIDTH, RATE>,
+    _spec: PhantomData<S>,
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize> MerklePathChip<F, S, WIDTH, RATE>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+{
+    pub fn configure(meta: &mut ConstraintSystem<F>) -> MerklePathConfig<F, WIDTH, RATE> {
+        let swap = [(); 3].map(|_| meta.advice_column());
+        swap.iter().for_each(|column| meta.enable_equality(*column));
+        let s_swap = meta.selector();
+
+        meta.create_gate("conditional swap", |meta| {
+            let s_swap = meta.query_selector(s_swap);
+            let bit = meta.query_advice(swap[0], Rotation::cur());
+            let node = meta.query_advice(swap[1], Rotation::cur());
+            let sibling = meta.query_advice(swap[2], Rotation::cur());
+            let left = meta.query_advice(swap[1], Rotation::next());
+            let right = meta.query_advice(swap[2], Rotation::next());
+
+            let one = Expression::Constant(F::one());
+            vec![
+                s_swap.clone() * bit.clone() * (one - bit.clone()),
+                s_swap.clone()
+                    * (node.clone() + bit.clone() * (sibling.clone() - node.clone()) - left),
+                s_swap * (sibling.clone() + bit * (node - sibling) - right),
+            ]
+        });
+
+        // Recomposes the index from its bits, most significant first:
+        // `acc = 2 * acc_prev + bit`.
+        let s_index = meta.selector();
+        meta.create_gate("index bits", |meta| {
+            let s_index = meta.query_selector(s_index);
+            let bit = meta.query_advice(swap[0], Rotation::cur());
+            let acc = meta.query_advice(swap[1], Rotation::cur());
+            let acc_prev = meta.query_advice(swap[1], Rotation::prev());
+
+            let two = Expression::Constant(F::from(2));
+            vec![s_index * (acc - (acc_prev * two + bit))]
+        });
+
+        // Compares bits, most significant first, with those of `p - 1`: `eq`
+        // stays one while the two agree, and a bit may only exceed that of
+        // `p - 1` once an earlier one has fallen below it.
+        let s_max_one = meta.selector();
+        let s_max_zero = meta.selector();
+        meta.create_gate("canonical bits", |meta| {
+            let s_max_one = meta.query_selector(s_max_one);
+            let s_max_zero = meta.query_selector(s_max_zero);
+            let bit = meta.query_advice(swap[0], Rotation::cur());
+            let eq = meta.query_advice(swap[1], Rotation::cur());
+            let eq_prev = meta.query_advice(swap[1], Rotation::prev());
+
+            let one = Expression::Constant(F::one());
+            vec![
+                s_max_one * (eq.clone() - eq_prev.clone() * bit.clone()),
+                s_max_zero.clone() * (eq - eq_prev.clone() * (one - bit.clone())),
+                s_max_zero * eq_prev * bit,
+            ]
+        });
+
+        let state = [(); WIDTH].map(|_| meta.advice_column());
+        let partial_sbox = meta.advice_column();
+        let rc_a = [(); WIDTH].map(|_| meta.fixed_column());
+        let rc_b = [(); WIDTH].map(|_| meta.fixed_column());
+        meta.enable_constant(rc_b[0]);
+
+        MerklePathConfig {
+            swap,
+            s_swap,
+            s_index,
+            s_max_one,
+            s_max_zero,
+            poseidon_config: Pow5Chip::configure::<S>(meta, state, partial_sbox, rc_a, rc_b),
+        }
+    }
+
+    pub fn construct(config: MerklePathConfig<F, WIDTH, RATE>) -> Self {
+        Self {
+            config,
+            _spec: PhantomData,
+        }
+    }
+
+    /// Rows taken by one node hash: the sponge's initial state, then for
+    /// each block of `RATE` words the absorbed block and the Pow5 permutation
+    /// (one row per full round and per two partial rounds, plus its output),
+    /// and the `WIDTH` initial-state and the padding constants.
+    pub fn rows_per_hash() -> usize {
+        let padding = <ConstantLength<2> as Domain<F, RATE>>::padding(2).len();
+        let blocks = (2 + padding) / RATE;
+        1 + blocks * (2 + S::full_rounds() + S::partial_rounds() / 2 + 1) + WIDTH + padding
+    }
+
+    /// Rows taken by one `compute_root` over a path of height `N`.
+    ///
+    /// The swap regions sit in their own columns and are shorter than the
+    /// hashes, so only the Poseidon columns count towards the height.
+    pub fn rows_per_root<const N: usize>() -> usize {
+        N * Self::rows_per_hash()
+    }
+
+    /// Assigns the index bits and siblings of `path`, leaf level first.
+    pub fn load_path<const N: usize>(
+        &self,
+        layouter: impl Layouter<F>,
+        path: Value<AuthPath<F, N>>,
+    ) -> Result<(Vec<AssignedCell<F, F>>, Vec<AssignedCell<F, F>>), Error> {
+        self.load_bits(
+            layouter,
+            path.map(|path| array::from_fn(|level| (path.index >> level) & 1 == 1)),
+            path.map(|path| path.siblings),
+        )
+    }
+
+    /// Assigns `bits` and `siblings`, leaf level first, for paths whose
+    /// position does not fit a `u64` index.
+    pub fn load_bits<const N: usize>(
+        &self,
+        mut layouter: impl Layouter<F>,
+        bits: Value<[bool; N]>,
+        siblings: Value<[F; N]>,
+    ) -> Result<(Vec<AssignedCell<F, F>>, Vec<AssignedCell<F, F>>), Error> {
+        layouter.assign_region(
+            || "load path",
+            |mut region| {
+                let mut bit_cells = Vec::with_capacity(N);
+                let mut sibling_cells = Vec::with_capacity(N);
+                for level in 0..N {
+                    bit_cells.push(region.assign_advice(
+                        || format!("bit_{}", level),
+                        self.config.swap[0],
+                        level,
+                        || bits.map(|bits| F::from(bits[level] as u64)),
+                    )?);
+                    sibling_cells.push(region.assign_advice(
+                        || format!("sibling_{}", level),
+                        self.config.swap[2],
+                        level,
+                        || siblings.map(|siblings| siblings[level]),
+                    )?);
+                }
+                Ok((bit_cells, sibling_cells))
+            },
+        )
+    }
+
+    /// Constrains `bits`, leaf level first, to be the binary form of `index`.
+    ///
+    /// `load_path` leaves the bits free, which is enough when the index is
+    /// private; a circuit that makes claims about the index binds it here.
+    /// The bits are only range checked by `compute_root`, so this is meant
+    /// for bits that are also hashed along.
+    pub fn constrain_index(
+        &self,
+        mut layouter: impl Layouter<F>,
+        bits: &[AssignedCell<F, F>],
+        index: &AssignedCell<F, F>,
+    ) -> Result<(), Error> {
+        layouter.assign_region(
+            || "index bits",
+            |mut region| {
+                let mut acc = region.assign_advice_from_constant(
+                    || "acc_0",
+                    self.config.swap[1],
+                    0,
+                    F::zero(),
+                )?;
+                for (i, bit) in bits.iter().rev().enumerate() {
+                    let row = i + 1;
+                    self.config.s_index.enable(&mut region, row)?;
+                    let bit = bit.copy_advice(|| "bit", &mut region, self.config.swap[0], row)?;
+                    let value = acc
+                        .value()
+                        .zip(bit.value())
+                        .map(|(acc, bit)| *acc + *acc + *bit);
+                    acc = region.assign_advice(
+                        || format!("acc_{}", row),
+                        self.config.swap[1],
+                        row,
+                        || value,
+                    )?;
+                }
+                region.constrain_equal(acc.cell(), index.cell())
+            },
+        )
+    }
+
+    /// Constrains `bits`, leaf level first, to be the canonical binary form of
+    /// `value`, one bit per bit of the field.
+    ///
+    /// `constrain_index` alone would also accept the bits of `value + p`
+    /// whenever that fits, which would give a key two positions in a tree
+    /// keyed by field elements; this also checks that the bits are at most
+    /// `p - 1`. As there, the bits are range checked by `compute_root`.
+    pub fn constrain_canonical(
+        &self,
+        mut layouter: impl Layouter<F>,
+        bits: &[AssignedCell<F, F>],
+        value: &AssignedCell<F, F>,
+    ) -> Result<(), Error> {
+        assert_eq!(bits.len(), F::NUM_BITS as usize);
+        self.constrain_index(layouter.namespace(|| "recompose"), bits, value)?;
+
+        let max = (-F::one()).to_repr();
+        layouter.assign_region(
+            || "canonical bits",
+            |mut region| {
+                let mut eq = region.assign_advice_from_constant(
+                    || "eq_0",
+                    self.config.swap[1],
+                    0,
+                    F::one(),
+                )?;
+                for (i, bit) in bits.iter().enumerate().rev() {
+                    let row = bits.len() - i;
+                    let max_bit = (max.as_ref()[i / 8] >> (i % 8)) & 1 == 1;
+                    if max_bit {
+                        self.config.s_max_one.enable(&mut region, row)?;
+                    } else {
+                        self.config.s_max_zero.enable(&mut region, row)?;
+                    }
+                    let bit = bit.copy_advice(|| "bit", &mut region, self.config.swap[0], row)?;
+                    let value = eq.value().zip(bit.value()).map(|(eq, bit)| {
+                        if max_bit {
+                            *eq * bit
+                        } else {
+                            *eq * (F::one() - bit)
+                        }
+                    });
+                    eq = region.assign_advice(
+                        || format!("eq_{}", row),
+                        self.config.swap[1],
+                        row,
+                        || value,
+                    )?;
+                }
+                Ok(())
+            },
+        )
+    }
+
+    /// Returns the cell holding the root of `leaf` along `bits` and `siblings`.
+    pub fn compute_root(
+        &self,
+        mut layouter: impl Layouter<F>,
+        leaf: &AssignedCell<F, F>,
+        bits: &[AssignedCell<F, F>],
+        siblings: &[AssignedCell<F, F>],
+    ) -> Result<AssignedCell<F, F>, Error> {
+        assert_eq!(bits.len(), siblings.len());
+
+        let mut node = leaf.clone();
+        for (level, (bit, sibling)) in bits.iter().zip(siblings.iter()).enumerate() {
+            let (left, right) = self.swap(
+                layouter.namespace(|| format!("swap level {}", level)),
+                bit,
+                &node,
+                sibling,
+            )?;
+
+            node = self.hash(
+                layouter.namespace(|| format!("hash level {}", level)),
+                left,
+                right,
+            )?;
+        }
+        Ok(node)
+    }
+
+    /// Returns `(node, sibling)` when `bit` is zero and `(sibling, node)`
+    /// when it is one, constraining `bit` to be boolean.
+    fn swap(
+        &self,
+        mut layouter: impl Layouter<F>,
+        bit: &AssignedCell<F, F>,
+        node: &AssignedCell<F, F>,
+        sibling: &AssignedCell<F, F>,
+    ) -> Result<(AssignedCell<F, F>, AssignedCell<F, F>), Error> {
+        layouter.assign_region(
+            || "swap",
+            |mut region| {
+                self.config.s_swap.enable(&mut region, 0)?;
+                let bit = bit.copy_advice(|| "bit", &mut region, self.config.swap[0], 0)?;
+                let node = node.copy_advice(|| "node", &mut region, self.config.swap[1], 0)?;
+                let sibling =
+                    sibling.copy_advice(|| "sibling", &mut region, self.config.swap[2], 0)?;
+
+                let swapped = bit.value().copied().map(|bit| bit == F::one());
+                let (node, sibling) = (node.value().copied(), sibling.value().copied());
+                let left = region.assign_advice(
+                    || "left",
+                    self.config.swap[1],
+                    1,
+                    || {
+                        swapped.zip(node).zip(sibling).map(
+                            |((swapped, node), sibling)| {
+                                if swapped {
+                                    sibling
+                                } else {
+                                    node
+                                }
+                            },
+                        )
+                    },
+                )?;
+                let right = region.assign_advice(
+                    || "right",
+                    self.config.swap[2],
+                    1,
+                    || {
+                        swapped.zip(node).zip(sibling).map(
+                            |((swapped, node), sibling)| {
+                                if swapped {
+                                    node
+                                } else {
+                                    sibling
+                                }
+                            },
+                        )
+                    },
+                )?;
+                Ok((left, right))
+            },
+        )
+    }
+
+    /// Returns a cell holding `when_set` if `bit` is one and `when_unset` if
+    /// it is zero, constraining `bit` to be boolean.
+    pub(crate) fn select(
+        &self,
+        layouter: impl Layouter<F>,
+        bit: &AssignedCell<F, F>,
+        when_unset: &AssignedCell<F, F>,
+        when_set: &AssignedCell<F, F>,
+    ) -> Result<AssignedCell<F, F>, Error> {
+        let (selected, _) = self.swap(layouter, bit, when_unset, when_set)?;
+        Ok(selected)
+    }
+
+    /// Returns the cell holding the root of `empty_leaf` along `bits` and
+    /// `siblings`, i.e. the root of a tree whose slot at the path's index is
+    /// unused.
+    ///
+    /// The empty leaf is loaded as a constant, so the prover cannot substitute
+    /// another value for it.
+    pub fn compute_empty_root(
+        &self,
+        mut layouter: impl Layouter<F>,
+        empty_leaf: F,
+        bits: &[AssignedCell<F, F>],
+        siblings: &[AssignedCell<F, F>],
+    ) -> Result<AssignedCell<F, F>, Error> {
+        let leaf = layouter.assign_region(
+            || "load empty leaf",
+            |mut region| {
+                region.assign_advice_from_constant(
+                    || "empty leaf",
+                    self.config.swap[1],
+                    0,
+                    empty_leaf,
+                )
+            },
+        )?;
+        self.compute_root(layouter, &leaf, bits, siblings)
+    }
+
+    pub(crate) fn hash(
+        &self,
+        mut layouter: impl Layouter<F>,
+        left: AssignedCell<F, F>,
+        right: AssignedCell<F, F>,
+    ) -> Result<AssignedCell<F, F>, Error> {
+        let chip = Pow5Chip::construct(self.config.poseidon_config.clone());
+        let mut sponge = Sponge::<_, _, S, _, ConstantLength<2>, WIDTH, RATE>::new(
+            chip,
+            layouter.namespace(|| "init"),
+        )?;
+        sponge.absorb(
+            layouter.namespace(|| "absorb left"),
+            PaddedWord::Message(left),
+        )?;
+        sponge.absorb(
+            layouter.namespace(|| "absorb right"),
+            PaddedWord::Message(right),
+        )?;
+        // Empty when `RATE == 2`, but the native hash pads to whole blocks at
+        // any other rate.
+        for (i, pad) in <ConstantLength<2> as Domain<F, RATE>>::padding(2)
+            .into_iter()
+            .enumerate()
+        {
+            sponge.absorb(
+                layouter.namespace(|| format!("absorb padding {}", i)),
+                PaddedWord::Padding(pad),
+            )?;
+        }
+        sponge
+            .finish_absorbing(layouter.namespace(|| "finish absorbing"))?
+            .squeeze(layouter.namespace(|| "squeeze"))
+    }
+}
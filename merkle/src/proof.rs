@@ -0,0 +1,426 @@
+//! A versioned encoding of Merkle proofs, so that a path can be saved or sent
+//! to a wallet and checked there against the state root.
+//!
+//! The binary form is a header of the version, the proof kind and the tree
+//! height (one byte each) and the leaf index (eight bytes, little endian),
+//! followed by the leaves of the proof (none for non-membership, the leaf for
+//! membership, the old and new leaf for an update) and the `N` siblings from
+//! the leaf level up. The JSON form has the same fields. Field elements use
+//! `hash::encoding` in both forms, as in the node stores: the 32-byte
+//! little-endian representation, written as `0x`-prefixed hex in JSON.
+
+use halo2_proofs::arithmetic::FieldExt;
+use hash::encoding::{
+    field_from_bytes, field_from_hex, field_to_bytes, field_to_hex, DecodeError, FIELD_BYTES,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use smt::poseidon::FieldHasher;
+use std::fmt;
+
+use crate::batch::LeafUpdate;
+use crate::insert::NonMembershipProof;
+use crate::path::AuthPath;
+use crate::store::NodeStore;
+use crate::tree::{MerkleTree, TreeError};
+
+/// The version written by `to_bytes` and serde; the only one read back.
+pub const PROOF_VERSION: u8 = 1;
+
+const MEMBERSHIP: u8 = 0;
+const NON_MEMBERSHIP: u8 = 1;
+const UPDATE: u8 = 2;
+const HEADER_BYTES: usize = 11;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProofError {
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    WrongHeight { expected: usize, actual: usize },
+    InvalidLength { expected: usize, actual: usize },
+    WrongNodeCount { expected: usize, actual: usize },
+    IndexOutOfRange(u64),
+    InvalidField(DecodeError),
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofError::UnsupportedVersion(version) => {
+                write!(f, "unsupported proof version {}", version)
+            }
+            ProofError::UnknownKind(kind) => write!(f, "unknown proof kind {}", kind),
+            ProofError::WrongHeight { expected, actual } => {
+                write!(f, "expected a proof of height {}, got {}", expected, actual)
+            }
+            ProofError::InvalidLength { expected, actual } => {
+                write!(f, "expected {} bytes, got {}", expected, actual)
+            }
+            ProofError::WrongNodeCount { expected, actual } => {
+                write!(f, "expected {} field elements, got {}", expected, actual)
+            }
+            ProofError::IndexOutOfRange(index) => {
+                write!(f, "leaf index {} is outside the tree", index)
+            }
+            ProofError::InvalidField(err) => write!(f, "invalid field element: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ProofError {}
+
+impl From<DecodeError> for ProofError {
+    fn from(err: DecodeError) -> Self {
+        ProofError::InvalidField(err)
+    }
+}
+
+/// A proof about one leaf of a tree of height `N`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Proof<F: FieldExt, const N: usize> {
+    /// `leaf` is at the path's index.
+    Membership { leaf: F, path: AuthPath<F, N> },
+    /// The slot at the path's index is empty.
+    NonMembership(NonMembershipProof<F, N>),
+    /// The leaf at the path's index went from `old_leaf` to `new_leaf`.
+    Update(LeafUpdate<F, N>),
+}
+
+/// Checks `proof` against `root`, the state root read from the chain.
+///
+/// A membership or non-membership proof is checked against the current root.
+/// An update proof is checked against the root after the update, so a wallet
+/// can see that the last batch wrote its new leaf; `Proof::old_root` gives
+/// the root it replaced. `empty_leaf` is the tree's, never the prover's:
+/// taking it from the proof would let any leaf pass as empty.
+pub fn verify_proof<F, H, const N: usize>(
+    root: F,
+    proof: &Proof<F, N>,
+    empty_leaf: F,
+    hasher: &H,
+) -> bool
+where
+    F: FieldExt,
+    H: FieldHasher<F, 2>,
+{
+    match proof {
+        Proof::Membership { leaf, path } => path.root(*leaf, hasher) == root,
+        Proof::NonMembership(proof) => proof.verify(root, empty_leaf, hasher),
+        Proof::Update(update) => update.new_root(hasher) == root,
+    }
+}
+
+/// Reads a root stored on chain as a 32-byte big-endian word, i.e. the
+/// `uint256` the `Rollup` contract stores.
+pub fn root_from_bytes<F: FieldExt>(bytes: &[u8]) -> Result<F, ProofError> {
+    let mut word = bytes.to_vec();
+    word.reverse();
+    Ok(field_from_bytes(&word)?)
+}
+
+fn check_length(bytes: &[u8], expected: usize) -> Result<(), ProofError> {
+    if bytes.len() != expected {
+        return Err(ProofError::InvalidLength {
+            expected,
+            actual: bytes.len(),
+        });
+    }
+    Ok(())
+}
+
+impl<F: FieldExt, const N: usize> Proof<F, N> {
+    /// `N`, checked at compile time to fit the one-byte height field.
+    const HEIGHT: u8 = {
+        assert!(
+            N <= u8::MAX as usize,
+            "a proof encodes its height in one byte"
+        );
+        N as u8
+    };
+
+    pub fn path(&self) -> &AuthPath<F, N> {
+        match self {
+            Proof::Membership { path, .. } => path,
+            Proof::NonMembership(proof) => &proof.path,
+            Proof::Update(update) => &update.path,
+        }
+    }
+
+    pub fn index(&self) -> u64 {
+        self.path().index
+    }
+
+    /// The root before an update, or `None` for the other proofs.
+    pub fn old_root<H: FieldHasher<F, 2>>(&self, hasher: &H) -> Option<F> {
+        match self {
+            Proof::Update(update) => Some(update.old_root(hasher)),
+            _ => None,
+        }
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            Proof::Membership { .. } => MEMBERSHIP,
+            Proof::NonMembership(_) => NON_MEMBERSHIP,
+            Proof::Update(_) => UPDATE,
+        }
+    }
+
+    fn leaves(&self) -> Vec<F> {
+        match self {
+            Proof::Membership { leaf, .. } => vec![*leaf],
+            Proof::NonMembership(_) => vec![],
+            Proof::Update(update) => vec![update.old_leaf, update.new_leaf],
+        }
+    }
+
+    fn from_parts(kind: u8, leaves: &[F], path: AuthPath<F, N>) -> Result<Self, ProofError> {
+        match kind {
+            MEMBERSHIP => Ok(Proof::Membership {
+                leaf: leaves[0],
+                path,
+            }),
+            NON_MEMBERSHIP => Ok(Proof::NonMembership(NonMembershipProof { path })),
+            UPDATE => Ok(Proof::Update(LeafUpdate {
+                path,
+                old_leaf: leaves[0],
+                new_leaf: leaves[1],
+            })),
+            _ => Err(ProofError::UnknownKind(kind)),
+        }
+    }
+
+    fn num_leaves(kind: u8) -> Result<usize, ProofError> {
+        match kind {
+            MEMBERSHIP => Ok(1),
+            NON_MEMBERSHIP => Ok(0),
+            UPDATE => Ok(2),
+            _ => Err(ProofError::UnknownKind(kind)),
+        }
+    }
+
+    fn check_header(version: u8, height: usize, index: u64) -> Result<(), ProofError> {
+        if version != PROOF_VERSION {
+            return Err(ProofError::UnsupportedVersion(version));
+        }
+        if height != N {
+            return Err(ProofError::WrongHeight {
+                expected: N,
+                actual: height,
+            });
+        }
+        if N < 64 && index >> N != 0 {
+            return Err(ProofError::IndexOutOfRange(index));
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![PROOF_VERSION, self.kind(), Self::HEIGHT];
+        bytes.extend_from_slice(&self.index().to_le_bytes());
+        for node in self.leaves().iter().chain(self.path().siblings.iter()) {
+            bytes.extend_from_slice(&field_to_bytes(node));
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProofError> {
+        if bytes.len() < HEADER_BYTES {
+            return Err(ProofError::InvalidLength {
+                expected: HEADER_BYTES,
+                actual: bytes.len(),
+            });
+        }
+        let (version, kind, height) = (bytes[0], bytes[1], bytes[2] as usize);
+        let mut index = [0u8; 8];
+        index.copy_from_slice(&bytes[3..HEADER_BYTES]);
+        let index = u64::from_le_bytes(index);
+        Self::check_header(version, height, index)?;
+
+        let num_leaves = Self::num_leaves(kind)?;
+        check_length(bytes, HEADER_BYTES + (num_leaves + N) * FIELD_BYTES)?;
+        let nodes = bytes[HEADER_BYTES..]
+            .chunks(FIELD_BYTES)
+            .map(field_from_bytes)
+            .collect::<Result<Vec<F>, _>>()?;
+
+        let mut siblings = [F::zero(); N];
+        siblings.copy_from_slice(&nodes[num_leaves..]);
+        Self::from_parts(kind, &nodes[..num_leaves], AuthPath { siblings, index })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    Membership,
+    NonMembership,
+    Update,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncodedProof {
+    version: u8,
+    kind: Kind,
+    height: usize,
+    index: u64,
+    leaves: Vec<String>,
+    siblings: Vec<String>,
+}
+
+impl<F: FieldExt, const N: usize> Serialize for Proof<F, N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let kind = match self {
+            Proof::Membership { .. } => Kind::Membership,
+            Proof::NonMembership(_) => Kind::NonMembership,
+            Proof::Update(_) => Kind::Update,
+        };
+        EncodedProof {
+            version: PROOF_VERSION,
+            kind,
+            height: Self::HEIGHT as usize,
+            index: self.index(),
+            leaves: self.leaves().iter().map(field_to_hex).collect(),
+            siblings: self.path().siblings.iter().map(field_to_hex).collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, F: FieldExt, const N: usize> Deserialize<'de> for Proof<F, N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = EncodedProof::deserialize(deserializer)?;
+        let kind = match encoded.kind {
+            Kind::Membership => MEMBERSHIP,
+            Kind::NonMembership => NON_MEMBERSHIP,
+            Kind::Update => UPDATE,
+        };
+        let decode = |strings: &[String], expected: usize| {
+            if strings.len() != expected {
+                return Err(ProofError::WrongNodeCount {
+                    expected,
+                    actual: strings.len(),
+                });
+            }
+            strings
+                .iter()
+                .map(|s| Ok(field_from_hex(s)?))
+                .collect::<Result<Vec<F>, ProofError>>()
+        };
+
+        let proof =
+            Self::check_header(encoded.version, encoded.height, encoded.index).and_then(|_| {
+                let leaves = decode(&encoded.leaves, Self::num_leaves(kind)?)?;
+                let mut siblings = [F::zero(); N];
+                siblings.copy_from_slice(&decode(&encoded.siblings, N)?);
+                Self::from_parts(
+                    kind,
+                    &leaves,
+                    AuthPath {
+                        siblings,
+                        index: encoded.index,
+                    },
+                )
+            });
+        proof.map_err(de::Error::custom)
+    }
+}
+
+impl<F, H, const N: usize, S> MerkleTree<F, H, N, S>
+where
+    F: FieldExt,
+    H: FieldHasher<F, 2>,
+    S: NodeStore<F>,
+{
+    /// A membership proof for the leaf at `index`, or a non-membership proof
+    /// if the slot is empty.
+    pub fn proof(&self, index: u64) -> Result<Proof<F, N>, TreeError<S::Error>> {
+        Ok(match self.non_membership_proof(index)? {
+            Some(proof) => Proof::NonMembership(proof),
+            None => Proof::Membership {
+                leaf: self.leaf(index)?,
+                path: self.path(index)?,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use halo2_proofs::arithmetic::Field;
+    use halo2_proofs::halo2curves::bn256::Fr;
+    use halo2_proofs::halo2curves::ff::PrimeField;
+    use rand::rngs::OsRng;
+    use smt::poseidon::Poseidon;
+
+    #[test]
+    fn proof_encoding_test() {
+        const HEIGHT: usize = 8;
+
+        let hasher = Poseidon::<Fr, 2>::new();
+        let mut tree = MerkleTree::<Fr, _, HEIGHT>::new(Fr::zero(), hasher.clone());
+        tree.set(3, Fr::random(OsRng)).unwrap();
+        let update = tree.update(200, Fr::random(OsRng)).unwrap();
+        let root = tree.root().unwrap();
+
+        let proofs = [
+            tree.proof(3).unwrap(),
+            tree.proof(4).unwrap(),
+            Proof::Update(update),
+        ];
+        assert!(matches!(proofs[0], Proof::Membership { .. }));
+        assert!(matches!(proofs[1], Proof::NonMembership(_)));
+
+        // The root as the contract returns it.
+        let mut word = root.to_repr();
+        word.reverse();
+        let root = root_from_bytes::<Fr>(&word).unwrap();
+
+        for proof in proofs.iter() {
+            assert!(verify_proof(root, proof, Fr::zero(), &hasher));
+
+            let bytes = proof.to_bytes();
+            assert_eq!(Proof::<Fr, HEIGHT>::from_bytes(&bytes).unwrap(), *proof);
+            let json = serde_json::to_string(proof).unwrap();
+            assert!(json.contains(&field_to_hex(&proof.path().siblings[0])));
+            assert_eq!(
+                serde_json::from_str::<Proof<Fr, HEIGHT>>(&json).unwrap(),
+                *proof
+            );
+        }
+        assert_eq!(proofs[2].old_root(&hasher), Some(update.old_root(&hasher)));
+
+        // Neither another leaf nor another empty leaf verifies.
+        let forged = Proof::Membership {
+            leaf: Fr::one(),
+            path: *proofs[0].path(),
+        };
+        assert!(!verify_proof(root, &forged, Fr::zero(), &hasher));
+        assert!(!verify_proof(root, &proofs[1], Fr::one(), &hasher));
+
+        let mut bytes = proofs[0].to_bytes();
+        bytes[0] = PROOF_VERSION + 1;
+        assert_eq!(
+            Proof::<Fr, HEIGHT>::from_bytes(&bytes),
+            Err(ProofError::UnsupportedVersion(PROOF_VERSION + 1))
+        );
+        assert_eq!(
+            Proof::<Fr, 16>::from_bytes(&proofs[0].to_bytes()),
+            Err(ProofError::WrongHeight {
+                expected: 16,
+                actual: HEIGHT
+            })
+        );
+        let mut bytes = proofs[2].to_bytes();
+        bytes[1] = UPDATE + 1;
+        assert_eq!(
+            Proof::<Fr, HEIGHT>::from_bytes(&bytes),
+            Err(ProofError::UnknownKind(UPDATE + 1))
+        );
+        let bytes = proofs[1].to_bytes();
+        assert!(matches!(
+            Proof::<Fr, HEIGHT>::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ProofError::InvalidLength { .. })
+        ));
+    }
+}
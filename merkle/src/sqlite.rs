@@ -0,0 +1,192 @@
+use halo2_proofs::arithmetic::FieldExt;
+use hash::encoding::{field_from_bytes, field_to_bytes};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use crate::store::{
+    node_from_bytes, AddressNodeChange, AddressStore, NodeChange, NodeStore, StoreError,
+};
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Sqlite(e)
+    }
+}
+
+/// Keeps the nodes in a `merkle_node` table, one row per non-empty node, and
+/// those of an `AddressTree` in a `merkle_address_node` table.
+///
+/// Each write runs in a single transaction, so a tree is never left with
+/// half of an update after a crash.
+#[derive(Debug)]
+pub struct SqliteStore<F: FieldExt> {
+    connection: Connection,
+    _field: PhantomData<F>,
+}
+
+impl<F: FieldExt> SqliteStore<F> {
+    /// Opens the database at `path`, creating the table if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    /// Uses an already open database, e.g. the operator's own.
+    pub fn from_connection(connection: Connection) -> Result<Self, StoreError> {
+        connection.execute(
+            "create table if not exists merkle_node (
+                level integer not null,
+                idx integer not null,
+                node blob not null,
+                primary key (level, idx)
+            )",
+            (),
+        )?;
+        connection.execute(
+            "create table if not exists merkle_address_node (
+                level integer not null,
+                key blob not null,
+                node blob not null,
+                primary key (level, key)
+            )",
+            (),
+        )?;
+        Ok(Self {
+            connection,
+            _field: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> Result<usize, StoreError> {
+        let len: i64 = self.connection.query_row(
+            "select (select count(*) from merkle_node) + (select count(*) from merkle_address_node)",
+            (),
+            |row| row.get(0),
+        )?;
+        Ok(len as usize)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, StoreError> {
+        Ok(self.len()? == 0)
+    }
+}
+
+impl<F: FieldExt> NodeStore<F> for SqliteStore<F> {
+    type Error = StoreError;
+
+    fn get(&self, level: usize, index: u64) -> Result<Option<F>, StoreError> {
+        let bytes: Option<Vec<u8>> = self
+            .connection
+            .query_row(
+                "select node from merkle_node where level = ?1 and idx = ?2",
+                params![level as i64, index as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        bytes
+            .map(|bytes| node_from_bytes(&bytes, level, index))
+            .transpose()
+    }
+
+    fn write(&mut self, changes: &[NodeChange<F>]) -> Result<(), StoreError> {
+        let tx = self.connection.transaction()?;
+        for (level, index, node) in changes {
+            match node {
+                Some(node) => tx.execute(
+                    "insert or replace into merkle_node (level, idx, node) values (?1, ?2, ?3)",
+                    params![*level as i64, *index as i64, &field_to_bytes(node)[..]],
+                )?,
+                None => tx.execute(
+                    "delete from merkle_node where level = ?1 and idx = ?2",
+                    params![*level as i64, *index as i64],
+                )?,
+            };
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl<F: FieldExt> AddressStore<F> for SqliteStore<F> {
+    type Error = StoreError;
+
+    fn get_address_node(&self, level: usize, key: &[u8]) -> Result<Option<F>, StoreError> {
+        let bytes: Option<Vec<u8>> = self
+            .connection
+            .query_row(
+                "select node from merkle_address_node where level = ?1 and key = ?2",
+                params![level as i64, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        bytes
+            .map(|bytes| {
+                field_from_bytes(&bytes).map_err(|_| StoreError::InvalidAddressNode {
+                    level,
+                    key: key.to_vec(),
+                })
+            })
+            .transpose()
+    }
+
+    fn write_address_nodes(&mut self, changes: &[AddressNodeChange<F>]) -> Result<(), StoreError> {
+        let tx = self.connection.transaction()?;
+        for (level, key, node) in changes {
+            match node {
+                Some(node) => tx.execute(
+                    "insert or replace into merkle_address_node (level, key, node) values (?1, ?2, ?3)",
+                    params![*level as i64, key, &field_to_bytes(node)[..]],
+                )?,
+                None => tx.execute(
+                    "delete from merkle_address_node where level = ?1 and key = ?2",
+                    params![*level as i64, key],
+                )?,
+            };
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MerkleTree;
+    use halo2_proofs::arithmetic::Field;
+    use halo2_proofs::halo2curves::bn256::Fr;
+    use rand::rngs::OsRng;
+    use smt::poseidon::Poseidon;
+
+    #[test]
+    fn sqlite_store_test() {
+        const HEIGHT: usize = 32;
+        type Tree = MerkleTree<Fr, Poseidon<Fr, 2>, HEIGHT, SqliteStore<Fr>>;
+
+        let path = std::env::temp_dir().join(format!("merkle-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let open = || {
+            Tree::with_store(
+                SqliteStore::open(&path).unwrap(),
+                Fr::zero(),
+                Poseidon::new(),
+            )
+        };
+
+        let mut memory = MerkleTree::<Fr, _, HEIGHT>::new(Fr::zero(), Poseidon::<Fr, 2>::new());
+        let mut tree = open();
+        for index in [1, 1 << 20, (1 << 32) - 2] {
+            let leaf = Fr::random(OsRng);
+            tree.set(index, leaf).unwrap();
+            memory.set(index, leaf).unwrap();
+        }
+        assert_eq!(tree.root().unwrap(), memory.root().unwrap());
+        assert_eq!(tree.store().len().unwrap(), memory.store().len());
+        drop(tree);
+
+        let tree = open();
+        assert_eq!(tree.root().unwrap(), memory.root().unwrap());
+        assert_eq!(tree.path(1 << 20).unwrap(), memory.path(1 << 20).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,371 @@
+use halo2_proofs::arithmetic::FieldExt;
+use hash::encoding::{field_from_bytes, field_to_bytes, FIELD_BYTES};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// A node write: the level, the index within it, and the new node, or `None`
+/// when the node becomes empty.
+pub type NodeChange<F> = (usize, u64, Option<F>);
+
+/// Where a `MerkleTree` keeps its non-empty nodes, keyed by level (0 for the
+/// leaves) and index within the level.
+pub trait NodeStore<F: FieldExt> {
+    type Error: std::error::Error;
+
+    fn get(&self, level: usize, index: u64) -> Result<Option<F>, Self::Error>;
+
+    /// Applies all `changes` at once; `None` removes the node.
+    ///
+    /// A leaf write changes one node per level, so this is called with
+    /// `N + 1` changes per update.
+    fn write(&mut self, changes: &[NodeChange<F>]) -> Result<(), Self::Error>;
+}
+
+/// A node write in an `AddressTree`: the level, the key of the node, i.e. the
+/// little-endian bytes of the address bits from that level up, and the new
+/// node, or `None` when the node becomes empty.
+pub type AddressNodeChange<F> = (usize, Vec<u8>, Option<F>);
+
+/// Where an `AddressTree` keeps its non-empty nodes.
+///
+/// The nodes of a tree one level per address bit have keys too wide for a
+/// `u64` index, so they live apart from the `NodeStore` nodes, even in a
+/// store that holds both.
+pub trait AddressStore<F: FieldExt> {
+    type Error: std::error::Error;
+
+    fn get_address_node(&self, level: usize, key: &[u8]) -> Result<Option<F>, Self::Error>;
+
+    /// Applies all `changes` at once; `None` removes the node.
+    fn write_address_nodes(&mut self, changes: &[AddressNodeChange<F>]) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+    InvalidNode {
+        level: usize,
+        index: u64,
+    },
+    InvalidAddressNode {
+        level: usize,
+        key: Vec<u8>,
+    },
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "node store I/O failed: {}", e),
+            #[cfg(feature = "sqlite")]
+            StoreError::Sqlite(e) => write!(f, "node store query failed: {}", e),
+            StoreError::InvalidNode { level, index } => {
+                write!(
+                    f,
+                    "stored node {} at level {} is not a field element",
+                    index, level
+                )
+            }
+            StoreError::InvalidAddressNode { level, key } => {
+                write!(
+                    f,
+                    "stored address node 0x{} at level {} is not a field element",
+                    hex::encode(key),
+                    level
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+pub(crate) fn node_from_bytes<F: FieldExt>(
+    bytes: &[u8],
+    level: usize,
+    index: u64,
+) -> Result<F, StoreError> {
+    field_from_bytes(bytes).map_err(|_| StoreError::InvalidNode { level, index })
+}
+
+/// Keeps the nodes in memory; the tree is lost when it is dropped.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore<F: FieldExt> {
+    nodes: HashMap<(usize, u64), F>,
+    address_nodes: HashMap<(usize, Vec<u8>), F>,
+}
+
+impl<F: FieldExt> MemoryStore<F> {
+    pub fn len(&self) -> usize {
+        self.nodes.len() + self.address_nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<F: FieldExt> NodeStore<F> for MemoryStore<F> {
+    type Error = Infallible;
+
+    fn get(&self, level: usize, index: u64) -> Result<Option<F>, Infallible> {
+        Ok(self.nodes.get(&(level, index)).copied())
+    }
+
+    fn write(&mut self, changes: &[NodeChange<F>]) -> Result<(), Infallible> {
+        for (level, index, node) in changes {
+            match node {
+                Some(node) => self.nodes.insert((*level, *index), *node),
+                None => self.nodes.remove(&(*level, *index)),
+            };
+        }
+        Ok(())
+    }
+}
+
+impl<F: FieldExt> AddressStore<F> for MemoryStore<F> {
+    type Error = Infallible;
+
+    fn get_address_node(&self, level: usize, key: &[u8]) -> Result<Option<F>, Infallible> {
+        Ok(self.address_nodes.get(&(level, key.to_vec())).copied())
+    }
+
+    fn write_address_nodes(&mut self, changes: &[AddressNodeChange<F>]) -> Result<(), Infallible> {
+        for (level, key, node) in changes {
+            match node {
+                Some(node) => self.address_nodes.insert((*level, key.clone()), *node),
+                None => self.address_nodes.remove(&(*level, key.clone())),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the nodes in memory and appends every write to a log file.
+///
+/// A write is logged as a little-endian `u32` count followed by one record
+/// per change: the level (one byte), the index (eight bytes, little endian), a
+/// presence byte and the node's canonical encoding. Reopening replays the log,
+/// so no node is rehashed, and drops a trailing write that was cut short.
+/// Each write is synced to disk before it returns, so it survives a crash.
+/// `compact` rewrites the log with only the live nodes once it has grown.
+///
+/// The whole log is replayed into memory on open, and only `NodeStore` is
+/// implemented, so this backs a `MerkleTree` but not an `AddressTree`; use
+/// `SqliteStore` for the latter.
+#[derive(Debug)]
+pub struct FileStore<F: FieldExt> {
+    nodes: MemoryStore<F>,
+    path: PathBuf,
+    log: BufWriter<File>,
+}
+
+impl<F: FieldExt> FileStore<F> {
+    /// Opens the log at `path`, creating it if it does not exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let path = path.as_ref().to_path_buf();
+        let mut nodes = MemoryStore::default();
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(&path)?);
+            let mut complete = 0;
+            while let Some((changes, len)) = Self::read_batch(&mut reader)? {
+                nodes.write(&changes).unwrap();
+                complete += len;
+            }
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(complete)?;
+        }
+
+        let log = BufWriter::new(OpenOptions::new().create(true).append(true).open(&path)?);
+        Ok(Self { nodes, path, log })
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Rewrites the log with one record per live node.
+    pub fn compact(&mut self) -> Result<(), StoreError> {
+        let tmp = self.path.with_extension("compact");
+        {
+            let changes: Vec<_> = self
+                .nodes
+                .nodes
+                .iter()
+                .map(|((level, index), node)| (*level, *index, Some(*node)))
+                .collect();
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            writer.write_all(&Self::encode_batch(&changes))?;
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        self.log = BufWriter::new(OpenOptions::new().append(true).open(&self.path)?);
+        Ok(())
+    }
+
+    fn record_len() -> usize {
+        1 + 8 + 1 + FIELD_BYTES
+    }
+
+    fn encode_batch(changes: &[NodeChange<F>]) -> Vec<u8> {
+        let mut batch = (changes.len() as u32).to_le_bytes().to_vec();
+        for (level, index, node) in changes {
+            batch.extend(Self::encode(*level, *index, *node));
+        }
+        batch
+    }
+
+    /// Reads one logged write and its length in bytes, or `None` at the end of
+    /// the log or of its last complete write.
+    fn read_batch(reader: &mut impl Read) -> Result<Option<(Vec<NodeChange<F>>, u64)>, StoreError> {
+        let mut count = [0u8; 4];
+        if !Self::read_full(reader, &mut count)? {
+            return Ok(None);
+        }
+        let count = u32::from_le_bytes(count) as usize;
+
+        let mut record = vec![0u8; Self::record_len()];
+        let mut changes = Vec::new();
+        for _ in 0..count {
+            if !Self::read_full(reader, &mut record)? {
+                return Ok(None);
+            }
+            changes.push(Self::decode(&record)?);
+        }
+        Ok(Some((changes, (4 + count * Self::record_len()) as u64)))
+    }
+
+    fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool, StoreError> {
+        match reader.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn encode(level: usize, index: u64, node: Option<F>) -> Vec<u8> {
+        let mut record = Vec::with_capacity(Self::record_len());
+        record.push(level as u8);
+        record.extend_from_slice(&index.to_le_bytes());
+        match node {
+            Some(node) => {
+                record.push(1);
+                record.extend_from_slice(&field_to_bytes(&node));
+            }
+            None => {
+                record.push(0);
+                record.resize(Self::record_len(), 0);
+            }
+        }
+        record
+    }
+
+    fn decode(record: &[u8]) -> Result<NodeChange<F>, StoreError> {
+        let level = record[0] as usize;
+        let mut index = [0u8; 8];
+        index.copy_from_slice(&record[1..9]);
+        let index = u64::from_le_bytes(index);
+        let node = match record[9] {
+            0 => None,
+            1 => Some(node_from_bytes(&record[10..], level, index)?),
+            _ => return Err(StoreError::InvalidNode { level, index }),
+        };
+        Ok((level, index, node))
+    }
+}
+
+impl<F: FieldExt> NodeStore<F> for FileStore<F> {
+    type Error = StoreError;
+
+    fn get(&self, level: usize, index: u64) -> Result<Option<F>, StoreError> {
+        Ok(self.nodes.get(level, index).unwrap())
+    }
+
+    fn write(&mut self, changes: &[NodeChange<F>]) -> Result<(), StoreError> {
+        self.log.write_all(&Self::encode_batch(changes))?;
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        self.nodes.write(changes).unwrap();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MerkleTree;
+    use halo2_proofs::arithmetic::Field;
+    use halo2_proofs::halo2curves::bn256::Fr;
+    use rand::rngs::OsRng;
+    use smt::poseidon::Poseidon;
+
+    #[test]
+    fn file_store_reopen_test() {
+        const HEIGHT: usize = 16;
+        type Tree = MerkleTree<Fr, Poseidon<Fr, 2>, HEIGHT, FileStore<Fr>>;
+
+        let path = std::env::temp_dir().join(format!("merkle-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let open =
+            || Tree::with_store(FileStore::open(&path).unwrap(), Fr::zero(), Poseidon::new());
+
+        let mut tree = open();
+        for index in [3, 70, 4000] {
+            tree.set(index, Fr::random(OsRng)).unwrap();
+        }
+        tree.set(70, Fr::zero()).unwrap();
+        let root = tree.root().unwrap();
+        let leaf = tree.leaf(4000).unwrap();
+        drop(tree);
+
+        // Reopening replays the log instead of rehashing the leaves.
+        let tree = open();
+        assert_eq!(tree.root().unwrap(), root);
+        assert_eq!(tree.leaf(4000).unwrap(), leaf);
+        // The two leaves share their ancestors from level 12 up.
+        assert_eq!(tree.store().len(), 2 * 12 + (HEIGHT + 1 - 12));
+
+        let mut store = tree.into_store();
+        let logged = fs::metadata(&path).unwrap().len();
+        store.compact().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < logged);
+        drop(store);
+
+        // A write cut short by a crash is dropped on reopen.
+        let mut tree = open();
+        tree.set(5, Fr::random(OsRng)).unwrap();
+        drop(tree);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        let tree = open();
+        assert_eq!(tree.root().unwrap(), root);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+use halo2_proofs::arithmetic::FieldExt;
+use smt::poseidon::FieldHasher;
+use std::convert::Infallible;
+use std::fmt;
+
+use crate::batch::LeafUpdate;
+use crate::insert::NonMembershipProof;
+use crate::multiproof::MultiProofError;
+use crate::path::AuthPath;
+use crate::store::{MemoryStore, NodeChange, NodeStore};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TreeError<E> {
+    /// The index is outside its level of the tree.
+    IndexOutOfRange(u64),
+    /// The level is above the root.
+    LevelOutOfRange(usize),
+    /// The indices cannot be opened together.
+    MultiProof(MultiProofError),
+    Store(E),
+}
+
+impl<E: fmt::Display> fmt::Display for TreeError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeError::IndexOutOfRange(index) => write!(f, "index {} is outside the tree", index),
+            TreeError::LevelOutOfRange(level) => write!(f, "level {} is above the root", level),
+            TreeError::MultiProof(e) => write!(f, "{}", e),
+            TreeError::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: std::error::Error> std::error::Error for TreeError<E> {}
+
+impl<E> From<E> for TreeError<E> {
+    fn from(e: E) -> Self {
+        TreeError::Store(e)
+    }
+}
+
+/// The height `N` of a tree whose leaves are indexed by `u64`, checked at
+/// compile time.
+pub(crate) struct Height<const N: usize>;
+
+impl<const N: usize> Height<N> {
+    pub(crate) const CHECKED: usize = {
+        assert!(N < 64, "leaf indices are u64");
+        N
+    };
+}
+
+/// A sparse Merkle tree of height `N` that only stores the nodes above
+/// non-empty leaves, in a `NodeStore`.
+///
+/// `empty[level]` caches the root of an all-empty subtree of that height, so
+/// an untouched sibling costs a lookup rather than `level` hashes and a leaf
+/// is written in `N` hashes and one store write whatever the height.
+#[derive(Clone, Debug)]
+pub struct MerkleTree<
+    F: FieldExt,
+    H: FieldHasher<F, 2>,
+    const N: usize,
+    S: NodeStore<F> = MemoryStore<F>,
+> {
+    store: S,
+    empty: Vec<F>,
+    hasher: H,
+}
+
+impl<F: FieldExt, H: FieldHasher<F, 2>, const N: usize> MerkleTree<F, H, N> {
+    pub fn new(empty_leaf: F, hasher: H) -> Self {
+        Self::with_store(MemoryStore::default(), empty_leaf, hasher)
+    }
+
+    /// Places `leaves` at indices `0..leaves.len()`.
+    pub fn from_leaves(
+        leaves: &[F],
+        empty_leaf: F,
+        hasher: H,
+    ) -> Result<Self, TreeError<Infallible>> {
+        let mut tree = Self::new(empty_leaf, hasher);
+        for (index, leaf) in leaves.iter().enumerate() {
+            tree.set(index as u64, *leaf)?;
+        }
+        Ok(tree)
+    }
+}
+
+impl<F, H, const N: usize, S> MerkleTree<F, H, N, S>
+where
+    F: FieldExt,
+    H: FieldHasher<F, 2>,
+    S: NodeStore<F>,
+{
+    /// Opens a tree over the nodes already in `store`, e.g. one persisted by
+    /// an earlier run. Nothing is rehashed.
+    pub fn with_store(store: S, empty_leaf: F, hasher: H) -> Self {
+        Self {
+            store,
+            empty: empty_hashes(empty_leaf, &hasher, Height::<N>::CHECKED),
+            hasher,
+        }
+    }
+
+    pub fn capacity() -> u64 {
+        1 << Height::<N>::CHECKED
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    pub fn hasher(&self) -> &H {
+        &self.hasher
+    }
+
+    pub fn empty_leaf(&self) -> F {
+        self.empty[0]
+    }
+
+    /// The root of a tree whose leaves are all empty.
+    pub fn empty_root(&self) -> F {
+        self.empty[N]
+    }
+
+    /// The node at `index` among the `2^(N - level)` nodes of `level`, where
+    /// level 0 holds the leaves.
+    pub fn node(&self, level: usize, index: u64) -> Result<F, TreeError<S::Error>> {
+        if level > N {
+            return Err(TreeError::LevelOutOfRange(level));
+        }
+        if index >> (N - level) != 0 {
+            return Err(TreeError::IndexOutOfRange(index));
+        }
+        Ok(self.get(level, index)?)
+    }
+
+    pub fn leaf(&self, index: u64) -> Result<F, TreeError<S::Error>> {
+        self.node(0, index)
+    }
+
+    pub fn root(&self) -> Result<F, S::Error> {
+        self.get(N, 0)
+    }
+
+    /// The siblings of the leaf at `index`, leaf level first.
+    pub fn path(&self, index: u64) -> Result<AuthPath<F, N>, TreeError<S::Error>> {
+        if index >= Self::capacity() {
+            return Err(TreeError::IndexOutOfRange(index));
+        }
+
+        let mut siblings = [F::zero(); N];
+        for (level, sibling) in siblings.iter_mut().enumerate() {
+            *sibling = self.get(level, (index >> level) ^ 1)?;
+        }
+        Ok(AuthPath { siblings, index })
+    }
+
+    /// Shows the slot at `index` is empty, or returns `None` if it is not.
+    pub fn non_membership_proof(
+        &self,
+        index: u64,
+    ) -> Result<Option<NonMembershipProof<F, N>>, TreeError<S::Error>> {
+        if self.leaf(index)? != self.empty_leaf() {
+            return Ok(None);
+        }
+        Ok(Some(NonMembershipProof {
+            path: self.path(index)?,
+        }))
+    }
+
+    /// Writes `leaf` at `index` and returns the new root.
+    ///
+    /// The `N + 1` nodes on the path go to the store in a single write; nodes
+    /// that become empty are removed rather than stored.
+    pub fn set(&mut self, index: u64, leaf: F) -> Result<F, TreeError<S::Error>> {
+        let path = self.path(index)?;
+
+        let mut changes = Vec::with_capacity(N + 1);
+        let mut node = leaf;
+        for (level, sibling) in path.siblings.iter().enumerate() {
+            changes.push(self.change(level, index >> level, node));
+            let pair = if (index >> level) & 1 == 1 {
+                [*sibling, node]
+            } else {
+                [node, *sibling]
+            };
+            node = self.hasher.hash(pair).unwrap();
+        }
+        changes.push(self.change(N, 0, node));
+
+        self.store.write(&changes)?;
+        Ok(node)
+    }
+
+    /// Writes `leaf` at `index` and returns the update, with the path taken
+    /// before the write, for an update circuit.
+    pub fn update(&mut self, index: u64, leaf: F) -> Result<LeafUpdate<F, N>, TreeError<S::Error>> {
+        let update = LeafUpdate {
+            path: self.path(index)?,
+            old_leaf: self.leaf(index)?,
+            new_leaf: leaf,
+        };
+        self.set(index, leaf)?;
+        Ok(update)
+    }
+
+    /// A node whose position is known to be inside the tree.
+    fn get(&self, level: usize, index: u64) -> Result<F, S::Error> {
+        Ok(self.store.get(level, index)?.unwrap_or(self.empty[level]))
+    }
+
+    fn change(&self, level: usize, index: u64, node: F) -> NodeChange<F> {
+        (level, index, (node != self.empty[level]).then_some(node))
+    }
+}
+
+/// The roots of all-empty subtrees of height `0..=height`.
+pub(crate) fn empty_hashes<F: FieldExt, H: FieldHasher<F, 2>>(
+    empty_leaf: F,
+    hasher: &H,
+    height: usize,
+) -> Vec<F> {
+    let mut empty = Vec::with_capacity(height + 1);
+    empty.push(empty_leaf);
+    for level in 0..height {
+        empty.push(hasher.hash([empty[level], empty[level]]).unwrap());
+    }
+    empty
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MerkleCircuit, LEAF_ROW, ROOT_ROW};
+    use halo2_proofs::arithmetic::Field;
+    use halo2_proofs::dev::MockProver;
+    use halo2_proofs::halo2curves::bn256::Fr;
+    use rand::rngs::OsRng;
+    use smt::poseidon::{Poseidon, SmtP128Pow5T3};
+    use smt::smt::SparseMerkleTree;
+
+    #[test]
+    fn tree_matches_smt_test() {
+        const HEIGHT: usize = 3;
+
+        let hasher = Poseidon::<Fr, 2>::new();
+        let leaves: Vec<Fr> = (0..5).map(|_| Fr::random(OsRng)).collect();
+        let smt = SparseMerkleTree::<Fr, Poseidon<Fr, 2>, HEIGHT>::new_sequential(
+            &leaves, &hasher, &[0u8; 64],
+        )
+        .unwrap();
+        let empty_leaf = smt.generate_membership_proof(7).path[0].1;
+
+        let tree =
+            MerkleTree::<Fr, _, HEIGHT>::from_leaves(&leaves, empty_leaf, hasher.clone()).unwrap();
+        assert_eq!(tree.root().unwrap(), smt.root());
+        for index in 0..8 {
+            let path = AuthPath::from_smt(&smt.generate_membership_proof(index), index);
+            assert_eq!(tree.path(index).unwrap(), path);
+        }
+        assert!(tree.non_membership_proof(4).unwrap().is_none());
+        assert!(tree.non_membership_proof(5).unwrap().is_some());
+
+        // Every index-taking method rejects an index past the last leaf.
+        let mut tree = tree;
+        let outside = Err(TreeError::IndexOutOfRange(8));
+        assert_eq!(tree.leaf(8), outside);
+        assert_eq!(tree.node(1, 4), Err(TreeError::IndexOutOfRange(4)));
+        assert_eq!(
+            tree.node(HEIGHT + 1, 0),
+            Err(TreeError::LevelOutOfRange(HEIGHT + 1))
+        );
+        assert_eq!(tree.path(8), Err(TreeError::IndexOutOfRange(8)));
+        assert_eq!(
+            tree.non_membership_proof(8),
+            Err(TreeError::IndexOutOfRange(8))
+        );
+        assert_eq!(tree.set(8, Fr::one()), outside);
+        assert_eq!(
+            tree.update(8, Fr::one()),
+            Err(TreeError::IndexOutOfRange(8))
+        );
+        assert_eq!(
+            MerkleTree::<Fr, _, HEIGHT>::from_leaves(&[Fr::one(); 9], empty_leaf, hasher).err(),
+            Some(TreeError::IndexOutOfRange(8))
+        );
+    }
+
+    #[test]
+    fn height_32_test() {
+        const HEIGHT: usize = 32;
+        type TestCircuit = MerkleCircuit<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2, HEIGHT>;
+
+        let hasher = Poseidon::<Fr, 2>::new();
+        let mut tree = MerkleTree::<Fr, _, HEIGHT>::new(Fr::zero(), hasher.clone());
+        let empty_root = tree.root().unwrap();
+
+        let indices = [0, 5, (1 << 31) + 7, (1 << 32) - 1];
+        for index in indices {
+            let update = tree.update(index, Fr::random(OsRng)).unwrap();
+            assert_eq!(update.old_root(&hasher), empty_root);
+            assert_eq!(update.new_root(&hasher), tree.root().unwrap());
+            tree.set(index, Fr::zero()).unwrap();
+        }
+        // Clearing every leaf leaves nothing stored.
+        assert_eq!(tree.root().unwrap(), empty_root);
+        assert!(tree.store().is_empty());
+
+        for index in indices {
+            tree.set(index, Fr::random(OsRng)).unwrap();
+        }
+        let index = indices[2];
+        let circuit = TestCircuit::from_auth_path(
+            tree.path(index).unwrap(),
+            tree.leaf(index).unwrap(),
+            &hasher,
+        );
+        let instances = circuit.instances();
+        assert_eq!(instances[0][ROOT_ROW], tree.root().unwrap());
+        assert_eq!(instances[0][LEAF_ROW], tree.leaf(index).unwrap());
+
+        let k = TestCircuit::min_k();
+        assert!(k > MerkleCircuit::<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2, 3>::min_k());
+        let prover = MockProver::run(k, &circuit, instances).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+    }
+}
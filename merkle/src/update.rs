@@ -0,0 +1,233 @@
+use halo2_gadgets::poseidon::primitives::Spec;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::circuit::{AssignedCell, Layouter, SimpleFloorPlanner, Value};
+use halo2_proofs::plonk::{Advice, Circuit, Column, ConstraintSystem, Error, Instance};
+use smt::poseidon::FieldHasher;
+use std::marker::PhantomData;
+
+use crate::path::{AuthPath, MerklePathChip, MerklePathConfig};
+
+pub const OLD_ROOT_ROW: usize = 0;
+pub const NEW_ROOT_ROW: usize = 1;
+
+#[derive(Clone, Debug)]
+pub struct MerkleUpdateConfig<F: FieldExt, const WIDTH: usize, const RATE: usize> {
+    path_config: MerklePathConfig<F, WIDTH, RATE>,
+    pub(crate) leaves: Column<Advice>,
+    pub(crate) roots: Column<Instance>,
+}
+
+impl<F: FieldExt, const WIDTH: usize, const RATE: usize> MerkleUpdateConfig<F, WIDTH, RATE> {
+    pub(crate) fn configure<S: Spec<F, WIDTH, RATE>>(meta: &mut ConstraintSystem<F>) -> Self {
+        let leaves = meta.advice_column();
+        meta.enable_equality(leaves);
+        let roots = meta.instance_column();
+        meta.enable_equality(roots);
+
+        Self {
+            path_config: MerklePathChip::<F, S, WIDTH, RATE>::configure(meta),
+            leaves,
+            roots,
+        }
+    }
+
+    pub(crate) fn chip<S: Spec<F, WIDTH, RATE>>(&self) -> MerklePathChip<F, S, WIDTH, RATE> {
+        MerklePathChip::construct(self.path_config.clone())
+    }
+}
+
+/// Proves that replacing `old_leaf` by `new_leaf` at the path's index moves
+/// the tree from the root at `OLD_ROOT_ROW` to the root at `NEW_ROOT_ROW`.
+///
+/// Both roots are computed over the same index bits and sibling cells, so the
+/// rest of the tree is unchanged by the update.
+#[derive(Clone, Debug)]
+pub struct MerkleUpdateCircuit<
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+    const WIDTH: usize,
+    const RATE: usize,
+    const N: usize,
+> {
+    path: Value<AuthPath<F, N>>,
+    old_leaf: Value<F>,
+    new_leaf: Value<F>,
+    old_root: F,
+    new_root: F,
+    _spec: PhantomData<S>,
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize>
+    MerkleUpdateCircuit<F, S, WIDTH, RATE, N>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+{
+    pub fn new<H: FieldHasher<F, 2>>(
+        path: AuthPath<F, N>,
+        old_leaf: F,
+        new_leaf: F,
+        hasher: &H,
+    ) -> Self {
+        Self {
+            path: Value::known(path),
+            old_leaf: Value::known(old_leaf),
+            new_leaf: Value::known(new_leaf),
+            old_root: path.root(old_leaf, hasher),
+            new_root: path.root(new_leaf, hasher),
+            _spec: PhantomData,
+        }
+    }
+
+    pub fn num_instance() -> Vec<usize> {
+        vec![2]
+    }
+
+    pub fn instances(&self) -> Vec<Vec<F>> {
+        let mut instances = vec![F::zero(); 2];
+        instances[OLD_ROOT_ROW] = self.old_root;
+        instances[NEW_ROOT_ROW] = self.new_root;
+        vec![instances]
+    }
+}
+
+impl<F, S, const WIDTH: usize, const RATE: usize, const N: usize> Circuit<F>
+    for MerkleUpdateCircuit<F, S, WIDTH, RATE, N>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE> + Clone,
+{
+    type Config = MerkleUpdateConfig<F, WIDTH, RATE>;
+    type FloorPlanner = SimpleFloorPlanner;
+
+    fn without_witnesses(&self) -> Self {
+        Self {
+            path: Value::unknown(),
+            old_leaf: Value::unknown(),
+            new_leaf: Value::unknown(),
+            old_root: F::zero(),
+            new_root: F::zero(),
+            _spec: PhantomData,
+        }
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        MerkleUpdateConfig::configure::<S>(meta)
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>,
+    ) -> Result<(), Error> {
+        let chip = config.chip::<S>();
+        let (old_root, new_root) = assign_update(
+            &chip,
+            layouter.namespace(|| "update"),
+            config.leaves,
+            self.path,
+            self.old_leaf,
+            self.new_leaf,
+        )?;
+
+        layouter.constrain_instance(old_root.cell(), config.roots, OLD_ROOT_ROW)?;
+        layouter.constrain_instance(new_root.cell(), config.roots, NEW_ROOT_ROW)
+    }
+}
+
+/// Assigns one leaf update and returns the cells of its old and new roots.
+///
+/// The two leaves go in consecutive rows of `leaves`, which must have
+/// equality enabled.
+pub(crate) fn assign_update<F, S, const WIDTH: usize, const RATE: usize, const N: usize>(
+    chip: &MerklePathChip<F, S, WIDTH, RATE>,
+    mut layouter: impl Layouter<F>,
+    leaves: Column<Advice>,
+    path: Value<AuthPath<F, N>>,
+    old_leaf: Value<F>,
+    new_leaf: Value<F>,
+) -> Result<(AssignedCell<F, F>, AssignedCell<F, F>), Error>
+where
+    F: FieldExt,
+    S: Spec<F, WIDTH, RATE>,
+{
+    let (old_leaf, new_leaf) = layouter.assign_region(
+        || "load leaves",
+        |mut region| {
+            let old_leaf = region.assign_advice(|| "old leaf", leaves, 0, || old_leaf)?;
+            let new_leaf = region.assign_advice(|| "new leaf", leaves, 1, || new_leaf)?;
+            Ok((old_leaf, new_leaf))
+        },
+    )?;
+    let (bits, siblings) = chip.load_path(layouter.namespace(|| "path"), path)?;
+
+    let old_root = chip.compute_root(
+        layouter.namespace(|| "old root"),
+        &old_leaf,
+        &bits,
+        &siblings,
+    )?;
+    let new_root = chip.compute_root(
+        layouter.namespace(|| "new root"),
+        &new_leaf,
+        &bits,
+        &siblings,
+    )?;
+    Ok((old_root, new_root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use halo2_proofs::arithmetic::Field;
+    use halo2_proofs::dev::MockProver;
+    use halo2_proofs::halo2curves::bn256::Fr;
+    use rand::rngs::OsRng;
+    use smt::poseidon::{Poseidon, SmtP128Pow5T3};
+    use smt::smt::SparseMerkleTree;
+
+    #[test]
+    fn merkle_update_test() {
+        const HEIGHT: usize = 3;
+        const K: u32 = 10;
+
+        let empty_leaf = [0u8; 64];
+        let hasher = Poseidon::<Fr, 2>::new();
+        let mut leaves: Vec<Fr> = (0..6).map(|_| Fr::random(OsRng)).collect();
+        let index = 3;
+
+        let smt = SparseMerkleTree::<Fr, Poseidon<Fr, 2>, HEIGHT>::new_sequential(
+            &leaves,
+            &hasher,
+            &empty_leaf,
+        )
+        .unwrap();
+        let path = AuthPath::from_smt(&smt.generate_membership_proof(index), index);
+
+        let old_leaf = leaves[index as usize];
+        let new_leaf = Fr::random(OsRng);
+        leaves[index as usize] = new_leaf;
+        let updated = SparseMerkleTree::<Fr, Poseidon<Fr, 2>, HEIGHT>::new_sequential(
+            &leaves,
+            &hasher,
+            &empty_leaf,
+        )
+        .unwrap();
+
+        let circuit = MerkleUpdateCircuit::<Fr, SmtP128Pow5T3<Fr, 0>, 3, 2, HEIGHT>::new(
+            path, old_leaf, new_leaf, &hasher,
+        );
+        let instances = circuit.instances();
+        assert_eq!(instances[0][OLD_ROOT_ROW], smt.root());
+        assert_eq!(instances[0][NEW_ROOT_ROW], updated.root());
+
+        let prover = MockProver::run(K, &circuit, instances.clone()).unwrap();
+        assert_eq!(prover.verify(), Ok(()));
+
+        // The update cannot be claimed against a different starting root.
+        let mut wrong = instances[0].clone();
+        wrong[OLD_ROOT_ROW] = wrong[NEW_ROOT_ROW];
+        let prover = MockProver::run(K, &circuit, vec![wrong]).unwrap();
+        assert!(prover.verify().is_err());
+    }
+}